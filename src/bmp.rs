@@ -0,0 +1,70 @@
+use alloc::format;
+use alloc::vec::Vec;
+use uefi::fs::Path;
+use uefi::proto::console::gop::BltPixel;
+use uefi::{cstr16, CStr16, CString16};
+use crate::error::Result;
+use crate::t;
+use crate::world::image_fs;
+
+/// Where `save_screenshot` puts its files
+const SCREENSHOT_DIR: &CStr16 = cstr16!("\\ueficraft\\screenshots");
+/// `shot_000.bmp` to `shot_999.bmp`
+const SCREENSHOT_SLOTS: u32 = 1000;
+
+const FILE_HEADER_LEN: usize = 14;
+const INFO_HEADER_LEN: usize = 40;
+const BITS_PER_PIXEL: u16 = 24;
+
+/// Bytes per row of a 24-bit image, padded to a multiple of 4
+fn row_stride(width: usize) -> usize {
+    (width * 3).div_ceil(4) * 4
+}
+
+/// Encodes a `width` wide top-down frame as an uncompressed 24-bit BMP.
+/// Rows are stored bottom-up in BGR order, as the format expects.
+pub fn encode(pixels: &[BltPixel], width: usize) -> Vec<u8> {
+    let height = pixels.len() / width;
+    let stride = row_stride(width);
+    let offset = FILE_HEADER_LEN + INFO_HEADER_LEN;
+    let size = offset + stride * height;
+
+    let mut out = Vec::with_capacity(size);
+    // BITMAPFILEHEADER
+    out.extend_from_slice(b"BM");
+    out.extend_from_slice(&(size as u32).to_le_bytes());
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&(offset as u32).to_le_bytes());
+    // BITMAPINFOHEADER, positive height means bottom-up
+    out.extend_from_slice(&(INFO_HEADER_LEN as u32).to_le_bytes());
+    out.extend_from_slice(&(width as i32).to_le_bytes());
+    out.extend_from_slice(&(height as i32).to_le_bytes());
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&BITS_PER_PIXEL.to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&((stride * height) as u32).to_le_bytes());
+    // Resolution and palette fields are optional
+    out.extend_from_slice(&[0; 16]);
+
+    for row in pixels.chunks_exact(width).rev() {
+        for p in row {
+            out.extend_from_slice(&[p.blue, p.green, p.red]);
+        }
+        out.resize(out.len() + stride - width * 3, 0);
+    }
+    out
+}
+
+/// Writes the frame to the first free `shot_NNN.bmp` under `SCREENSHOT_DIR`
+/// and returns its path, or `None` when every slot is taken
+pub fn save_screenshot(pixels: &[BltPixel], width: usize) -> Result<Option<CString16>> {
+    let mut fs = image_fs()?;
+    t!(fs.create_dir_all(SCREENSHOT_DIR));
+    for n in 0..SCREENSHOT_SLOTS {
+        let Ok(path) = CString16::try_from(format!("{}\\shot_{:03}.bmp", SCREENSHOT_DIR, n).as_str()) else { continue };
+        if t!(fs.try_exists(Path::new(&path))) { continue }
+        t!(fs.write(Path::new(&path), encode(pixels, width)));
+        return Ok(Some(path));
+    }
+    Ok(None)
+}
@@ -1,7 +1,15 @@
 use alloc::borrow::Cow;
-use core::time::Duration;
-use uefi::boot;
+use core::fmt::Write;
+use uefi::proto::console::text::{Key, ScanCode};
+use uefi::runtime::{self, ResetType};
+use uefi::{boot, system, Status};
+use crate::game::player_pos;
+use crate::render::{LineBuf, LOG_ERROR, PANIC_FG};
 use crate::Screen;
+use crate::world::CorruptionKind;
+
+/// 蓝屏上每行最多的字节数
+const PANIC_LINE_LEN: usize = 120;
 
 /// 返回结构的默认参数构造
 pub type Result<T = (), E = Error> = core::result::Result<T, E>;
@@ -12,6 +20,13 @@ pub const OK: Result = Ok(());
 #[derive(Debug)]
 pub enum ErrorType {
     Uefi(uefi::Error),
+    Fs(uefi::fs::Error),
+    /// 存档文件损坏或截断
+    BadWorldData,
+    /// 世界数据结构自检失败
+    Corrupt(crate::world::WorldCorruption),
+    /// 方块数到了 World::max_blocks 上限
+    WorldFull(crate::world::WorldFull),
     _Reserve,
 }
 
@@ -78,25 +93,68 @@ impl From<uefi::Error> for ErrorType {
     }
 }
 
-/// 捕获错误并打印 LSP识别不了宏对模块的使用（恼
-#[allow(unused_variables, unused_imports)]
-pub fn kernel_panic(scr:&mut Screen, e: Error) -> ! {
-    const SHUTDOWN_COUNTDOWN_MIN: u64 = 1;
+impl From<uefi::fs::Error> for ErrorType {
+    fn from(e: uefi::fs::Error) -> Self {
+        ErrorType::Fs(e)
+    }
+}
 
-    use alloc::format;
-    macro_rules! println {
-        ($($arg:tt)*) => {{
-            let _ = scr.println(&alloc::format!($($arg)*));
-        }} // 打印出错我也不管了
+impl From<crate::world::WorldFull> for ErrorType {
+    fn from(e: crate::world::WorldFull) -> Self {
+        ErrorType::WorldFull(e)
     }
+}
 
-    println!("Kernel panic at {}:{}\n{:?}", e.file, e.line, e.info);
-    match e.err {
-        ErrorType::Uefi(e) => println!("UEFI error: {}", e),
-        _ => println!("Error: {:?}", e.err),
+impl From<crate::world::WorldCorruption> for ErrorType {
+    fn from(e: crate::world::WorldCorruption) -> Self {
+        ErrorType::Corrupt(e)
     }
+}
 
-    println!("Kernel will shutdown in {} minute(s).", SHUTDOWN_COUNTDOWN_MIN);
-    boot::stall(Duration::from_mins(SHUTDOWN_COUNTDOWN_MIN));
-    panic!("Kernel Panic!");
-}
\ No newline at end of file
+/// 捕获错误，整屏显示蓝屏信息，按任意键重启，Esc 返回固件
+/// 堆和帧缓冲可能已经坏了，这里只用栈和 GOP
+pub fn kernel_panic(scr:&mut Screen, e: Error) -> ! {
+    // 格式化出错我也不管了，截断就截断
+    let mut location = LineBuf::<PANIC_LINE_LEN>::default();
+    let _ = write!(location, "Kernel panic at {}:{}", e.file, e.line);
+    let mut message = LineBuf::<PANIC_LINE_LEN>::default();
+    let _ = write!(message, "{}", e.info.as_deref().unwrap_or("(no message)"));
+    let mut status = LineBuf::<PANIC_LINE_LEN>::default();
+    let _ = match e.err {
+        ErrorType::Uefi(e) => write!(status, "UEFI error: {}", e),
+        ErrorType::Fs(e) => write!(status, "File system error: {}", e),
+        ErrorType::Corrupt(c) => match c.kind {
+            CorruptionKind::Duplicate(first) =>
+                write!(status, "World corrupt at entry {} {:?}: duplicate of entry {}", c.index, c.pos, first),
+            kind => write!(status, "World corrupt at entry {} {:?}: {:?}", c.index, c.pos, kind),
+        },
+        ErrorType::WorldFull(f) => write!(status, "World full: {} blocks (limit {})", f.blocks, f.max_blocks),
+        _ => write!(status, "Error: {:?}", e.err),
+    };
+    let mut player = LineBuf::<PANIC_LINE_LEN>::default();
+    let _ = match player_pos() {
+        Some(p) => write!(player, "Player at {:.2} {:.2} {:.2}", p.x, p.y, p.z),
+        None => write!(player, "Player not spawned yet"),
+    };
+
+    scr.show_panic(&[
+        (location.as_str(), LOG_ERROR),
+        (message.as_str(), PANIC_FG),
+        (status.as_str(), PANIC_FG),
+        (player.as_str(), PANIC_FG),
+        ("", PANIC_FG),
+        ("Press any key to reboot, Esc to return to firmware", PANIC_FG),
+    ]);
+
+    let key = system::with_stdin(|stdin| {
+        let _ = stdin.reset(false);
+        let mut events = [stdin.wait_for_key_event()?];
+        boot::wait_for_event(&mut events).ok()?;
+        stdin.read_key().ok().flatten()
+    });
+    if key == Some(Key::Special(ScanCode::ESCAPE)) {
+        // 不用清理了，回到固件 (或 Shell) 就行
+        unsafe { boot::exit(boot::image_handle(), Status::ABORTED, 0, core::ptr::null_mut()) }
+    }
+    runtime::reset(ResetType::COLD, Status::ABORTED, None)
+}
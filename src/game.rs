@@ -1,25 +1,321 @@
 use core::ffi::c_void;
+use core::fmt::{self, Write};
 use core::hint::spin_loop;
-use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicUsize, Ordering};
 use uefi::proto::pi::mp::MpServices;
-use uefi::proto::console::gop::{BltPixel, BltRegion};
+use uefi::proto::console::gop::BltPixel;
+use alloc::format;
+use alloc::string::{String, ToString};
 use alloc::vec::Vec;
-use glam::{Vec3, vec3, Mat4};
+use glam::{IVec2, IVec3, Vec2, Vec3, vec3, Mat3, Mat4};
+use crate::{bmp, time};
 use crate::error::{kernel_panic, OK, Result};
-use crate::render::Screen;
-use crate::world::World;
+use crate::render::{
+    draw_crosshair, draw_text, face_index, project, quantize, ray_march_heightmap, sample_texture, Billboard,
+    ColorGrade, FrameBuffers, Heightmap, LineBuf, Minimap, OverlayCamera, Screen, TextStyle, MINIMAP_SIZE,
+};
+use crate::world::{block_def, BlockSource, Crossing, Edit, log_axis, rgb, value_noise, World, WorldLock, GLASS, MAX_LIGHT, WATER};
 use crate::t;
 
 static PANIC_STATE: AtomicBool = AtomicBool::new(false);
 static NEXT_TILE: AtomicUsize = AtomicUsize::new(0);
-static DRAW_LOCK: AtomicBool = AtomicBool::new(false);
+/// Pixels of the current frame written to the back buffer
+static PIXELS_DONE: AtomicUsize = AtomicUsize::new(0);
+/// Hands each core running the frame its lane for the static work splits
+static NEXT_LANE: AtomicUsize = AtomicUsize::new(0);
+/// Work items each lane rendered this frame, for the balance overlay
+static WORK_BY_CORE: [AtomicUsize; MAX_CORES] = [const { AtomicUsize::new(0) }; MAX_CORES];
+/// Lanes tracked in `WORK_BY_CORE`, higher ones share the last slot
+const MAX_CORES: usize = 64;
+/// Outline thickness in blocks and how far it pulls the face colour towards white
+const OUTLINE_WIDTH: f32 = 0.04;
+const OUTLINE_MIX: f32 = 0.7;
+/// No player to limit reach yet, so anything up to the far plane can be targeted
+const TARGET_REACH: f32 = 100.0;
+const HUD_POS: (usize, usize) = (4, 4);
+const HUD_COLOR: BltPixel = BltPixel::new(255, 255, 255);
+/// Keeps the white HUD readable against the sky and clouds
+const HUD_STYLE: TextStyle = TextStyle::Outline(BltPixel::new(0, 0, 0));
+/// Distance of the work balance line from the bottom-left corner
+const WORK_STATS_MARGIN: usize = 4;
+/// First line of the debug overlay, under the HUD line, and the pitch of its lines
+const DEBUG_POS: (usize, usize) = (4, 24);
+const DEBUG_LINE_HEIGHT: usize = 18;
+/// A drop shadow is lighter than the HUD outline over several dense lines
+const DEBUG_STYLE: TextStyle = TextStyle::Shadow(BltPixel::new(0, 0, 0));
+/// Bytes per debug overlay line, longer lines are cut off
+const DEBUG_LINE_LEN: usize = 96;
+/// Gap between the minimap and the screen corner
+const MINIMAP_MARGIN: usize = 4;
+
+/// Hides overlays such as the crosshair and HUD
+pub static CINEMATIC: AtomicBool = AtomicBool::new(false);
+/// Shadow rays towards the sun and reflections off water, each a second trace for the pixels they touch
+pub static HIGH_QUALITY: AtomicBool = AtomicBool::new(false);
+/// Intersects sky rays with the cloud layer
+pub static CLOUDS: AtomicBool = AtomicBool::new(true);
+/// Top-down map of the columns around the camera in the top-right corner
+pub static MINIMAP: AtomicBool = AtomicBool::new(false);
+/// Multi-line readout of engine internals under the HUD
+pub static DEBUG_OVERLAY: AtomicBool = AtomicBool::new(false);
+/// Screenshots leave out the debug overlay
+pub static CLEAN_SHOT: AtomicBool = AtomicBool::new(false);
+/// Set to save the next presented frame as a BMP, cleared once it is written
+pub static SCREENSHOT: AtomicBool = AtomicBool::new(false);
+/// Frames started by core 0, drives anything that moves on its own
+static FRAME: AtomicU32 = AtomicU32::new(0);
+/// Vertical field of view in degrees as f32 bits, any core may change it between frames
+static FOV_Y: AtomicU32 = AtomicU32::new(DEFAULT_FOV_Y.to_bits());
+/// Block under the crosshair, valid while `TARGET_SET`
+static TARGET: [AtomicI32; 3] = [const { AtomicI32::new(0) }; 3];
+static TARGET_SET: AtomicBool = AtomicBool::new(false);
+/// Camera position of the frame being rendered as f32 bits, valid while `PLAYER_POS_SET`.
+/// Kept outside the context so the panic screen can still read it.
+static PLAYER_POS: [AtomicU32; 3] = [const { AtomicU32::new(0) }; 3];
+static PLAYER_POS_SET: AtomicBool = AtomicBool::new(false);
+/// Unit vector towards the sun as f32 bits, core 0 may move it between frames
+static SUN_DIR: [AtomicU32; 3] = [
+    AtomicU32::new(DEFAULT_SUN_DIR.x.to_bits()),
+    AtomicU32::new(DEFAULT_SUN_DIR.y.to_bits()),
+    AtomicU32::new(DEFAULT_SUN_DIR.z.to_bits()),
+];
+
+pub const DEFAULT_FOV_Y: f32 = 45.0;
+pub const FOV_Y_MIN: f32 = 30.0;
+pub const FOV_Y_MAX: f32 = 110.0;
+
+/// Afternoon, where `set_time_of_day(0.6)` puts the sun, already normalized. It is behind the
+/// default camera so the faces it sees are lit.
+pub const DEFAULT_SUN_DIR: Vec3 = vec3(-0.5630, 0.7749, -0.2873);
+/// The sun rises at +X and sets at -X, its path leaning this far towards -Z before normalizing
+const SUN_TILT: f32 = -0.3;
+/// Sun elevations (sine) from which the palette is fully day and fully night
+const DAY_ELEVATION: f32 = 0.3;
+const NIGHT_ELEVATION: f32 = 0.25;
+/// Floor of the sun term so faces turned away, or everything at night, stay visible
+const SUN_MIN: f32 = 0.15;
+const SUN_GLOW_MIX: f32 = 0.25;
+const SUN_COLOR: Vec3 = rgb(255, 250, 230);
+/// Cosines of the angle from the sun where its disk is solid and where the halo fades out
+const SUN_DISK: f32 = 0.9995;
+const SUN_HALO: f32 = 0.997;
+/// Shadow rays give up after this many blocks
+const SHADOW_DIST: f32 = 24.0;
+/// Shadow rays start this far out of the face so they don't hit their own block
+const SHADOW_BIAS: f32 = 1e-3;
+/// How much a fully enclosed face corner is darkened by ambient occlusion
+const AO_STRENGTH: f32 = 0.5;
+/// Neighbours of a face in its tangent plane, one `AoCache` mask bit each
+const AO_OFFSETS: [(i32, i32); 8] = [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)];
+
+pub const VIEW_DISTANCE_MIN: f32 = 8.0;
+pub const VIEW_DISTANCE_MAX: f32 = 512.0;
+
+/// Edge of the square tiles cores take from `NEXT_TILE`
+pub const TILE_SIZE: usize = 32;
+const CLOUD_Y: f32 = 96.0;
+const CLOUD_COLOR: Vec3 = rgb(245, 245, 250);
+const CLOUD_SHADE: Vec3 = rgb(200, 205, 215);
+/// Blocks per noise cell, noise sampled at `CLOUD_RES` points per block
+const CLOUD_CELL: i32 = 12;
+const CLOUD_RES: f32 = 4.0;
+/// Noise above which there is cloud, fully opaque `CLOUD_SOFT` above that
+const CLOUD_THRESHOLD: f32 = 0.6;
+const CLOUD_SOFT: f32 = 0.15;
+/// Blocks the layer moves along +X per frame
+const CLOUD_DRIFT: f32 = 0.05;
+/// Clouds fade into the sky until this far along the ray
+const CLOUD_FADE_DIST: f32 = 400.0;
+const CLOUD_SEED: u64 = 0xc10d;
+const DAY: SkyPalette = SkyPalette {
+    zenith: rgb(45, 95, 205), horizon: rgb(175, 205, 240), glow: rgb(255, 214, 160),
+    ambient: 0.35, diffuse: 0.65, clouds: 1.0,
+};
+const DAWN: SkyPalette = SkyPalette {
+    zenith: rgb(70, 90, 150), horizon: rgb(235, 180, 150), glow: rgb(255, 170, 120),
+    ambient: 0.25, diffuse: 0.5, clouds: 0.85,
+};
+const DUSK: SkyPalette = SkyPalette {
+    zenith: rgb(60, 60, 120), horizon: rgb(240, 150, 90), glow: rgb(255, 120, 60),
+    ambient: 0.25, diffuse: 0.5, clouds: 0.8,
+};
+const NIGHT: SkyPalette = SkyPalette {
+    zenith: rgb(4, 6, 18), horizon: rgb(18, 22, 40), glow: rgb(18, 22, 40),
+    ambient: SUN_MIN, diffuse: 0.0, clouds: 0.2,
+};
+const WATER_TINT_MIN: f32 = 0.25;
+const WATER_TINT_PER_BLOCK: f32 = 0.12;
+const WATER_TINT_MAX: f32 = 0.85;
+/// Pull towards white of the topmost water surface
+const WATER_SURFACE_HIGHLIGHT: f32 = 0.12;
+/// Reflectance of water looking straight down, rising to 1 at grazing angles
+const WATER_F0: f32 = 0.02;
+/// Reflected rays give up after this many blocks
+const REFLECT_DIST: f32 = 32.0;
+/// Pull towards the glass colour of each pane a ray enters, however thick
+const GLASS_TINT: f32 = 0.2;
+/// Gap along a ray under which two glass blocks count as one pane
+const GLASS_JOIN: f32 = 1e-3;
+/// Transparent blocks looked at per ray, so deep water can't blow the per-pixel budget
+const MAX_CROSSINGS: usize = 16;
+/// Brightness of faces with something solid above them
+const SKY_OCCLUDED: f32 = 0.55;
+/// Cut ends of logs are lighter than the bark
+const LOG_END_GRAIN: f32 = 1.25;
+/// Heatmap ramp from no block tests to a full pass over the block list
+const HEAT_COLD: Vec3 = rgb(0, 200, 0);
+const HEAT_WARM: Vec3 = rgb(255, 220, 0);
+const HEAT_HOT: Vec3 = rgb(230, 0, 0);
+/// Fill when the camera is inside a block
+const INSIDE_BLOCK_SHADE: f32 = 0.3;
+/// With the camera in water: how far it can see, and the fog towards that
+const UNDERWATER_VIEW: f32 = 16.0;
+const UNDERWATER_FOG_DENSITY: f32 = 0.2;
+/// What distant things fade into under water, scaled by daylight
+const UNDERWATER_FOG: Vec3 = rgb(20, 70, 90);
+/// Blue-green pull over the whole traced frame, not the HUD
+const UNDERWATER_TINT: Vec3 = rgb(40, 140, 150);
+const UNDERWATER_TINT_MIX: f32 = 0.3;
+/// Sideways ray offset per scanline in camera space, 0 turns the wobble off
+const UNDERWATER_WOBBLE: f32 = 0.004;
+/// Radians per pixel row and per frame
+const UNDERWATER_WOBBLE_FREQ: f32 = 0.05;
+const UNDERWATER_WOBBLE_SPEED: f32 = 0.3;
 
 #[repr(C)]
 pub struct GameContext<'bemly_> {
     pub mp: &'bemly_ MpServices,
     pub scr: &'bemly_ mut Screen,
     pub num_cores: usize,
-    pub world: World,
+    pub world: WorldLock,
+    /// Screen pixels per traced ray along each axis, a power of two up to the tile size
+    pub pixel_scale: usize,
+    pub render: RenderSettings,
+    pub frames: FrameBuffers,
+    pub rays: RayTable,
+    /// Column tops for `Renderer::Heightmap` and the minimap, core 0 rebuilds it after editing the world
+    pub heightmap: Heightmap,
+    /// Core 0 recentres it between frames
+    pub minimap: Minimap,
+    /// World markers drawn over each frame, core 0 changes them between frames
+    pub billboards: Vec<Billboard>,
+    /// Processor number of the bootstrap core, the only one that presents frames
+    pub bsp: usize,
+}
+
+/// How fog thickens between `fog_start` and `fog_end`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FogMode {
+    Linear,
+    /// `1 - e^(-density * d)`
+    Exp,
+    /// `1 - e^(-(density * d)^2)`, clear for longer then closing in faster
+    Exp2,
+}
+
+/// How the frame is shared out between cores. The static splits need every enabled core to
+/// run the frame, the queue doesn't.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WorkSplit {
+    /// One band of rows per core
+    ContiguousSlabs,
+    /// Core k takes every n-th row of cells starting at k
+    InterleavedRows,
+    /// `TILE_SIZE` tiles taken from a shared counter until none are left
+    TileQueue,
+}
+
+/// What turns the camera's view into pixels
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Renderer {
+    /// Every ray traced through the blocks, with all the lighting
+    Voxel,
+    /// Column spans from `render::ray_march_heightmap`, flat shaded, for slow machines
+    Heightmap,
+}
+
+/// What each pixel shows: the scene, or a debug view of how it was traced
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RenderMode {
+    Normal,
+    /// Block tests the primary ray ran until it found what it hits, green through yellow to
+    /// red for one pass over the opaque blocks, white past that when wrapped copies add more
+    Heatmap,
+    /// Hit distance as grey, white up close to black at the view distance and for sky
+    Depth,
+}
+
+/// Options fixed for the whole run, read by every core
+#[derive(Clone, Copy, Debug)]
+pub struct RenderSettings {
+    /// Core 0 may switch it between frames
+    pub renderer: Renderer,
+    /// Only the voxel renderer has debug views
+    pub mode: RenderMode,
+    pub fog_mode: FogMode,
+    /// Linear fog starts here
+    pub fog_start: f32,
+    /// Rays stop here and anything further is fully fogged, so this is the view distance
+    pub fog_end: f32,
+    /// Exp and Exp2 fog per block
+    pub fog_density: f32,
+    pub work_split: WorkSplit,
+    /// Ordered dithering when quantizing traced colours, against banding in the sky and fog
+    pub dither: bool,
+    /// The camera is in water this frame, see `publish_underwater`
+    pub underwater: bool,
+    /// Applied by `FrameBuffers::present` on top of everything
+    pub grade: ColorGrade,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            renderer: Renderer::Voxel,
+            mode: RenderMode::Normal,
+            fog_mode: FogMode::Linear,
+            fog_start: 48.0,
+            fog_end: 96.0,
+            fog_density: 0.03,
+            work_split: WorkSplit::TileQueue,
+            dither: true,
+            underwater: false,
+            grade: ColorGrade::None,
+        }
+    }
+}
+
+impl RenderSettings {
+    /// Sets `fog_end`, keeping where linear fog starts proportional to it
+    pub fn set_view_distance(&mut self, dist: f32) {
+        let dist = dist.clamp(VIEW_DISTANCE_MIN, VIEW_DISTANCE_MAX);
+        self.fog_start *= dist / self.fog_end;
+        self.fog_end = dist;
+    }
+
+    /// What a frame traces with: under water, dense short fog in place of the configured one.
+    /// `self` is left alone, so surfacing gets the configured fog back as it was.
+    fn submerged(self) -> Self {
+        if !self.underwater { return self }
+        Self {
+            fog_mode: FogMode::Exp,
+            fog_start: 0.0,
+            fog_end: self.fog_end.min(UNDERWATER_VIEW),
+            fog_density: UNDERWATER_FOG_DENSITY,
+            ..self
+        }
+    }
+
+    /// 0 for no fog up to 1 for only fog at `dist` blocks along a ray
+    fn fog(&self, dist: f32) -> f32 {
+        if dist >= self.fog_end { return 1.0 }
+        let exp = |x: f32| Vec3::splat(x).exp().x;
+        match self.fog_mode {
+            FogMode::Linear => ((dist - self.fog_start) / (self.fog_end - self.fog_start)).clamp(0.0, 1.0),
+            FogMode::Exp => 1.0 - exp(-self.fog_density * dist),
+            FogMode::Exp2 => 1.0 - exp(-(self.fog_density * dist) * (self.fog_density * dist)),
+        }
+    }
 }
 
 pub extern "efiapi" fn game_task(arg: *mut c_void) {
@@ -36,139 +332,772 @@ pub extern "efiapi" fn game_task(arg: *mut c_void) {
 }
 
 pub fn run(ctx: &mut GameContext) -> Result {
-    let (width, height) = ctx.scr.gop.current_mode_info().resolution();
+    let (width, height) = ctx.frames.size();
+    let screen = ctx.scr.gop.current_mode_info().resolution();
+    // Supersampled text and crosshair would be filtered into a blur, so they go on after
+    let tile_overlays = ctx.frames.supersample() == 1 && !CINEMATIC.load(Ordering::Relaxed);
+    let me = t!(ctx.mp.who_am_i());
+    let started = time::now_ms();
 
-    // Camera setup
-    let camera_pos = vec3(2.0, 3.0, 5.0);
-    let camera_target = vec3(0.0, 0.0, 0.0);
-    let camera_up = vec3(0.0, 1.0, 0.0);
+    let camera = Camera::overview(ctx);
+    let camera_pos = camera.pos;
+    let frame = Frame::current(ctx.render, &ctx.world);
+    if me == ctx.bsp {
+        for (axis, v) in PLAYER_POS.iter().zip(camera_pos.to_array()) {
+            axis.store(v.to_bits(), Ordering::Relaxed);
+        }
+        PLAYER_POS_SET.store(true, Ordering::Release);
+    }
 
-    let view = Mat4::look_at_rh(camera_pos, camera_target, camera_up);
-    let projection = Mat4::perspective_rh(45.0f32.to_radians(), width as f32 / height as f32, 0.1, 100.0);
-    let view_proj = projection * view;
+    let mut hud = format!(
+        "XYZ {:.1} {:.1} {:.1}  FOV {:.0}  VIEW {:.0}",
+        camera_pos.x, camera_pos.y, camera_pos.z, fov_y(), frame.settings.fog_end,
+    );
+    // So screenshots of a debug view say which one it is
+    if frame.settings.mode != RenderMode::Normal {
+        hud.push_str(&format!("  MODE {:?}", frame.settings.mode));
+    }
 
-    // Tile based rendering
-    let tile_size = 32;
-    let tiles_x = (width + tile_size - 1) / tile_size;
-    let tiles_y = (height + tile_size - 1) / tile_size;
-    let total_tiles = tiles_x * tiles_y;
+    let mut work = Work {
+        split: ctx.render.work_split,
+        screen: (width, height),
+        scale: ctx.pixel_scale,
+        cores: ctx.num_cores.max(1),
+        lane: NEXT_LANE.fetch_add(1, Ordering::Relaxed),
+        taken: 0,
+    };
+
+    while let Some((start, end)) = work.next() {
+        if PANIC_STATE.load(Ordering::Acquire) { break; }
+
+        let rect_w = end.0 - start.0;
+        let (mut buffer, depth) = match ctx.render.renderer {
+            Renderer::Voxel => render_rect(ctx, &camera, &frame, start, end),
+            Renderer::Heightmap => render_rect_heightmap(ctx, &camera, &frame, start, end),
+        };
+
+        if tile_overlays {
+            draw_crosshair(&mut buffer, start, rect_w, (width, height));
+            draw_text(&mut buffer, start, rect_w, HUD_POS, &hud, HUD_COLOR, HUD_STYLE);
+        }
+
+        ctx.frames.write_tile(start, rect_w, &buffer, &depth);
+        WORK_BY_CORE[work.lane.min(MAX_CORES - 1)].fetch_add(1, Ordering::Relaxed);
+        PIXELS_DONE.fetch_add(buffer.len(), Ordering::Release);
+    }
+
+    // Frame barrier: the bootstrap core presents once every core has written its tiles
+    if me == ctx.bsp {
+        while PIXELS_DONE.load(Ordering::Acquire) < width * height && !PANIC_STATE.load(Ordering::Acquire) {
+            spin_loop();
+        }
+        if !PANIC_STATE.load(Ordering::Acquire) {
+            let overlays = !CINEMATIC.load(Ordering::Relaxed);
+            if overlays {
+                let view = OverlayCamera { view_proj: &camera.view_proj, eye: camera.pos, focal: camera.focal };
+                ctx.frames.draw_over(|buffer, depth, width| {
+                    for billboard in &ctx.billboards {
+                        billboard.draw(buffer, depth, width, &view);
+                    }
+                });
+            }
+            let counts: Vec<String> = WORK_BY_CORE[..ctx.num_cores.min(MAX_CORES)].iter()
+                .map(|n| n.load(Ordering::Relaxed).to_string())
+                .collect();
+            let traced = time::now_ms() - started;
+            // Only known once every tile is in, so it goes on at present time right of the HUD line
+            let fps = format!("{} ms  {:.0} FPS", traced, 1000.0 / traced.max(1) as f32);
+            let fps_pos = (HUD_POS.0 + (hud.chars().count() + 2) * 8, HUD_POS.1);
+            let stats = format!("{:?} per core: {}  traced in {} ms", ctx.render.work_split, counts.join(" "), traced);
+            let stats_pos = (WORK_STATS_MARGIN, screen.1.saturating_sub(16 + WORK_STATS_MARGIN));
+            let supersampled = ctx.frames.supersample() > 1;
+            // Screenshots capture what was presented, so a clean shot presents without the debug overlay
+            let clean = SCREENSHOT.load(Ordering::Relaxed) && CLEAN_SHOT.load(Ordering::Relaxed);
+            let debug = (DEBUG_OVERLAY.load(Ordering::Relaxed) && !clean).then(|| DebugInfo::gather(ctx, &camera));
+            let minimap = MINIMAP.load(Ordering::Relaxed).then(|| {
+                let eye = (camera_pos + Vec3::splat(0.5)).floor().as_ivec3();
+                ctx.minimap.update(&ctx.heightmap, IVec2::new(eye.x, eye.z));
+                &ctx.minimap
+            });
+            let forward = camera.rot * Vec3::NEG_Z;
+            let facing = Vec2::new(forward.x, forward.z).normalize_or_zero();
+            ctx.frames.present(ctx.scr, ctx.render.grade, |buffer, width| {
+                if !overlays { return }
+                if supersampled {
+                    draw_crosshair(buffer, (0, 0), width, screen);
+                    draw_text(buffer, (0, 0), width, HUD_POS, &hud, HUD_COLOR, HUD_STYLE);
+                }
+                draw_text(buffer, (0, 0), width, fps_pos, &fps, HUD_COLOR, HUD_STYLE);
+                draw_text(buffer, (0, 0), width, stats_pos, &stats, HUD_COLOR, HUD_STYLE);
+                if let Some(debug) = &debug {
+                    debug.draw(buffer, width);
+                }
+                if let Some(minimap) = minimap {
+                    let corner = (width.saturating_sub(MINIMAP_SIZE + MINIMAP_MARGIN), MINIMAP_MARGIN);
+                    minimap.draw(buffer, width, corner, facing);
+                }
+            })?;
+            if SCREENSHOT.swap(false, Ordering::AcqRel) {
+                let (frame, width) = ctx.frames.presented();
+                match bmp::save_screenshot(frame, width) {
+                    Ok(Some(path)) => ctx.scr.println(&format!("Screenshot saved to {}", path))?,
+                    Ok(None) => ctx.scr.warn("Screenshot folder is full")?,
+                    Err(e) => ctx.scr.warn(&format!("Screenshot failed: {:?}", e.err))?,
+                }
+            }
+        }
+    }
 
+    // Wait loop
     loop {
         if PANIC_STATE.load(Ordering::Acquire) { break; }
+        spin_loop()
+    }
+    OK
+}
+
+/// What the debug overlay shows, gathered by core 0 after the frame barrier
+struct DebugInfo {
+    pos: Vec3,
+    /// Degrees, yaw from -Z towards +X and pitch up from the horizon
+    yaw: f32,
+    pitch: f32,
+    target: Option<(IVec3, &'static str)>,
+    last_edit: Option<Edit>,
+    renderer: Renderer,
+    work_split: WorkSplit,
+    cores: usize,
+    blocks: usize,
+    max_blocks: usize,
+    bytes_reserved: usize,
+}
 
-        let tile_idx = NEXT_TILE.fetch_add(1, Ordering::Relaxed);
-        if tile_idx >= total_tiles {
-            break; // Frame done
+impl DebugInfo {
+    fn gather(ctx: &GameContext, camera: &Camera) -> Self {
+        let forward = camera.rot * Vec3::NEG_Z;
+        let flat = Vec2::new(forward.x, forward.z).length();
+        let target = target();
+        let (target, last_edit, stats, max_blocks) = ctx.world.read(|world| {
+            let target = target.map(|pos| (pos, block_def(world.get_block(pos).id).name));
+            (target, world.recent_edits().next().copied(), world.stats(), world.max_blocks)
+        });
+        Self {
+            pos: camera.pos,
+            yaw: Vec2::new(-forward.z, forward.x).to_angle().to_degrees(),
+            pitch: Vec2::new(flat, forward.y).to_angle().to_degrees(),
+            target,
+            last_edit,
+            renderer: ctx.render.renderer,
+            work_split: ctx.render.work_split,
+            cores: ctx.num_cores,
+            blocks: stats.entries,
+            max_blocks,
+            bytes_reserved: stats.bytes_reserved,
         }
+    }
 
-        let ty = tile_idx / tiles_x;
-        let tx = tile_idx % tiles_x;
+    /// One line per `draw_text` call, each formatted on the stack
+    fn draw(&self, buffer: &mut [BltPixel], width: usize) {
+        let mut y = DEBUG_POS.1;
+        let mut line = |args: fmt::Arguments| {
+            let mut text = LineBuf::<DEBUG_LINE_LEN>::default();
+            let _ = text.write_fmt(args);
+            draw_text(buffer, (0, 0), width, (DEBUG_POS.0, y), text.as_str(), HUD_COLOR, DEBUG_STYLE);
+            y += DEBUG_LINE_HEIGHT;
+        };
+        let p = self.pos;
+        line(format_args!("XYZ {:.2} {:.2} {:.2}  yaw {:.1} pitch {:.1}", p.x, p.y, p.z, self.yaw, self.pitch));
+        match self.target {
+            Some((pos, name)) => line(format_args!("Looking at {} {} {} {}", name, pos.x, pos.y, pos.z)),
+            None => line(format_args!("Looking at nothing")),
+        }
+        if let Some(Edit { pos, old, new }) = self.last_edit {
+            let (old, new) = (block_def(old.id).name, block_def(new.id).name);
+            line(format_args!("Last edit {} -> {} at {} {} {}", old, new, pos.x, pos.y, pos.z));
+        }
+        line(format_args!("{:?} renderer, {:?} over {} cores", self.renderer, self.work_split, self.cores));
+        line(format_args!(
+            "Blocks {} / {}, {} KiB reserved", self.blocks, self.max_blocks, self.bytes_reserved / 1024,
+        ));
+    }
+}
 
-        let start_x = tx * tile_size;
-        let start_y = ty * tile_size;
-        let end_x = (start_x + tile_size).min(width);
-        let end_y = (start_y + tile_size).min(height);
+/// One core's share of the frame under a `WorkSplit`, as screen rectangles
+struct Work {
+    split: WorkSplit,
+    screen: (usize, usize),
+    /// Rectangles start on cell boundaries so cells stay square
+    scale: usize,
+    cores: usize,
+    /// This core's index among `cores`
+    lane: usize,
+    /// Rectangles handed out so far
+    taken: usize,
+}
 
-        let tile_w = end_x - start_x;
-        let tile_h = end_y - start_y;
+impl Work {
+    /// Start (inclusive) and end (exclusive) corners of the next rectangle, none once this core is done
+    fn next(&mut self) -> Option<((usize, usize), (usize, usize))> {
+        let (width, height) = self.screen;
+        let cell_rows = height.div_ceil(self.scale);
+        let rows = |first: usize, last: usize| ((0, first * self.scale), (width, (last * self.scale).min(height)));
+        let rect = match self.split {
+            WorkSplit::TileQueue => {
+                let tiles_x = width.div_ceil(TILE_SIZE);
+                let tile = NEXT_TILE.fetch_add(1, Ordering::Relaxed);
+                if tile >= tiles_x * height.div_ceil(TILE_SIZE) { return None }
+                let start = (tile % tiles_x * TILE_SIZE, tile / tiles_x * TILE_SIZE);
+                (start, ((start.0 + TILE_SIZE).min(width), (start.1 + TILE_SIZE).min(height)))
+            }
+            WorkSplit::ContiguousSlabs => {
+                if self.taken > 0 { return None }
+                rows(self.lane * cell_rows / self.cores, (self.lane + 1) * cell_rows / self.cores)
+            }
+            WorkSplit::InterleavedRows => {
+                let row = self.lane + self.taken * self.cores;
+                if row >= cell_rows { return None }
+                rows(row, row + 1)
+            }
+        };
+        self.taken += 1;
+        // More cores than rows leaves some slabs empty
+        if rect.0.1 >= rect.1.1 { return None }
+        Some(rect)
+    }
+}
 
-        let mut buffer = Vec::with_capacity((tile_w * tile_h) as usize);
+/// Traces the screen rectangle from `start` to `end` (exclusive) into pixel and depth buffers
+/// of its own size
+fn render_rect(
+    ctx: &GameContext, camera: &Camera, frame: &Frame, start: (usize, usize), end: (usize, usize),
+) -> (Vec<BltPixel>, Vec<f32>) {
+    let (start_x, start_y) = start;
+    let (end_x, end_y) = end;
+    let rect_w = end_x - start_x;
+    let scale = ctx.pixel_scale;
+    let mut buffer = alloc::vec![BltPixel::new(0, 0, 0); rect_w * (end_y - start_y)];
+    let mut depth = alloc::vec![f32::INFINITY; buffer.len()];
+    let underwater = frame.settings.underwater;
+    let phase = FRAME.load(Ordering::Relaxed) as f32 * UNDERWATER_WOBBLE_SPEED;
 
-        for y in start_y..end_y {
-            for x in start_x..end_x {
-                // Ray generation
-                let ndc_x = (x as f32 / width as f32) * 2.0 - 1.0;
-                let ndc_y = 1.0 - (y as f32 / height as f32) * 2.0;
+    // One read section per rectangle keeps the lock traffic off the per-pixel path
+    ctx.world.read(|world| {
+        // One ray per scale x scale cell, tiles are a multiple of the cell size
+        for cy in (start_y..end_y).step_by(scale) {
+            let mut ao = AoCache::default();
+            // Refraction-like sway, the same offset along the whole scanline
+            let wobble = underwater.then(|| {
+                Vec2::from_angle(cy as f32 * UNDERWATER_WOBBLE_FREQ + phase).y * UNDERWATER_WOBBLE
+            });
+            for cx in (start_x..end_x).step_by(scale) {
+                // The table already holds unit rays, only a swayed one needs normalizing again
+                let ray = ctx.rays.get((cx, cy), scale);
+                let ray_dir = camera.rot * wobble.map_or(ray, |w| (ray + Vec3::X * w).normalize());
 
-                let inv_vp = view_proj.inverse();
-                let target_world = inv_vp.project_point3(vec3(ndc_x, ndc_y, 1.0));
+                let (mut color, dist) = match frame.settings.mode {
+                    RenderMode::Normal => shade(world, camera.pos, ray_dir, frame, &mut ao),
+                    RenderMode::Heatmap | RenderMode::Depth => debug_view(world, camera.pos, ray_dir, frame),
+                };
+                if underwater && frame.settings.mode == RenderMode::Normal {
+                    color = color.lerp(UNDERWATER_TINT, UNDERWATER_TINT_MIX);
+                }
+                for y in cy..(cy + scale).min(end_y) {
+                    let row = (y - start_y) * rect_w;
+                    for x in cx..(cx + scale).min(end_x) {
+                        buffer[row + x - start_x] = quantize(color, (x, y), frame.settings.dither);
+                        depth[row + x - start_x] = dist;
+                    }
+                }
+            }
+        }
+    });
+    (buffer, depth)
+}
 
-                let ray_origin = camera_pos;
-                let ray_dir = (target_world - camera_pos).normalize();
+/// `render_rect` for `Renderer::Heightmap`. Each column of cells is one ground ray whose
+/// columns are drawn bottom up as spans in their top block's colour, then the sky fills in above.
+fn render_rect_heightmap(
+    ctx: &GameContext, camera: &Camera, frame: &Frame, start: (usize, usize), end: (usize, usize),
+) -> (Vec<BltPixel>, Vec<f32>) {
+    let (start_x, start_y) = start;
+    let (end_x, end_y) = end;
+    let rect_w = end_x - start_x;
+    let scale = ctx.pixel_scale;
+    let screen = ctx.frames.size();
+    let mut buffer = alloc::vec![BltPixel::new(0, 0, 0); rect_w * (end_y - start_y)];
+    let mut depth = alloc::vec![f32::INFINITY; buffer.len()];
+    let (sun, sky) = (frame.sun, &frame.sky);
+    let brightness = (sky.ambient + sky.diffuse * sun.y.max(0.0)).clamp(SUN_MIN, 1.0);
 
-                let mut r: u8 = 100;
-                let mut g: u8 = 149;
-                let mut b: u8 = 237;
+    for cx in (start_x..end_x).step_by(scale) {
+        let cols = cx..(cx + scale).min(end_x);
+        let mut put = |y: usize, color: Vec3, dist: f32| {
+            let row = (y - start_y) * rect_w;
+            for x in cols.clone() {
+                buffer[row + x - start_x] = quantize(color, (x, y), frame.settings.dither);
+                depth[row + x - start_x] = dist;
+            }
+        };
+        // The screen's middle row picks the ground ray, so every tile of a column agrees on it
+        let ground = camera.rot * ctx.rays.get((cx, screen.1 / 2), scale);
+        let flat = ground.with_y(0.0).normalize_or_zero();
+        // Rows from here down are drawn
+        let mut floor = end_y;
+        ray_march_heightmap(&ctx.heightmap, camera.pos, ground, frame.settings.fog_end, |top, id, dist| {
+            let at = (camera.pos + flat * dist).with_y(top.y as f32 + 0.5);
+            let Some((_, y, _)) = project(&camera.view_proj, at, screen, f32::INFINITY) else { return true };
+            let y = (y.max(0) as usize).clamp(start_y, floor);
+            let dist = at.distance(camera.pos);
+            let color = block_def(id).color * brightness;
+            let color = color.lerp(sky.horizon, frame.settings.fog(dist));
+            for row in y..floor {
+                put(row, color, dist);
+            }
+            floor = y;
+            floor > start_y
+        });
+        for cy in (start_y..floor).step_by(scale) {
+            let color = sky_color(camera.rot * ctx.rays.get((cx, cy), scale), sun, sky);
+            for row in cy..(cy + scale).min(floor) {
+                put(row, color, f32::INFINITY);
+            }
+        }
+    }
+    (buffer, depth)
+}
 
-                let mut min_dist = f32::MAX;
+/// Everything shading needs that core 0 may change between frames, read once per frame
+#[derive(Clone, Copy)]
+struct Frame {
+    /// Block to outline
+    target: Option<IVec3>,
+    sun: Vec3,
+    sky: SkyPalette,
+    /// Shadows and water reflections
+    high_quality: bool,
+    /// Drift of the cloud layer, none to skip it
+    clouds: Option<f32>,
+    settings: RenderSettings,
+    /// Distance travelled before the ray being shaded started, counted towards fog
+    path: f32,
+    /// Longest the ray being shaded may get, on top of the view distance
+    reach: f32,
+    /// `World::ray_tests_per_pass`, the heatmap's red end
+    tests_per_pass: usize,
+}
 
-                for (block_pos, block) in &ctx.world.blocks {
-                    let min = *block_pos - vec3(0.5, 0.5, 0.5);
-                    let max = *block_pos + vec3(0.5, 0.5, 0.5);
+impl Frame {
+    /// `settings` as configured, the frame applies the underwater overrides itself
+    fn current(settings: RenderSettings, world: &WorldLock) -> Self {
+        let sun = sun_dir();
+        Self {
+            settings: settings.submerged(),
+            target: target(),
+            sun,
+            sky: SkyPalette::at(sun),
+            high_quality: HIGH_QUALITY.load(Ordering::Relaxed),
+            clouds: CLOUDS.load(Ordering::Relaxed).then(|| FRAME.load(Ordering::Relaxed) as f32 * CLOUD_DRIFT),
+            path: 0.0,
+            reach: f32::INFINITY,
+            tests_per_pass: match settings.mode {
+                RenderMode::Heatmap => world.read(World::ray_tests_per_pass),
+                RenderMode::Normal | RenderMode::Depth => 0,
+            },
+        }
+    }
 
-                    if let Some(dist) = ray_aabb_intersect(ray_origin, ray_dir, min, max) {
-                        if dist > 0.0 && dist < min_dist {
-                            min_dist = dist;
-                            if block.id == 1 {
-                                r = 100; g = 100; b = 100;
-                            } else {
-                                r = 200; g = 50; b = 50;
-                            }
+    /// For a bounce `path` blocks into this frame's ray: no outline, no further bounces
+    /// or shadows, and at most `reach` blocks long
+    fn bounce(&self, path: f32, reach: f32) -> Self {
+        Self { target: None, high_quality: false, path: self.path + path, reach, ..*self }
+    }
+}
 
-                            // Simple lighting
-                            let hit_point = ray_origin + ray_dir * dist;
-                            let center = *block_pos;
-                            let local_hit = hit_point - center;
-                            let abs_hit = local_hit.abs();
+/// Sky colours and sun strength for one point of the day
+#[derive(Clone, Copy)]
+struct SkyPalette {
+    zenith: Vec3,
+    /// Also the haze below the horizon
+    horizon: Vec3,
+    /// Horizon colour looking straight towards the sun's azimuth
+    glow: Vec3,
+    ambient: f32,
+    diffuse: f32,
+    /// Brightness of the cloud layer
+    clouds: f32,
+}
 
-                            let mut brightness = 1.0;
-                            if abs_hit.x > abs_hit.y && abs_hit.x > abs_hit.z { brightness = 0.8; }
-                            else if abs_hit.y > abs_hit.x && abs_hit.y > abs_hit.z { brightness = 1.0; }
-                            else { brightness = 0.6; }
+impl SkyPalette {
+    /// Blends day, dawn or dusk and night by the elevation of `sun`
+    fn at(sun: Vec3) -> Self {
+        let twilight = if sun.x > 0.0 { DAWN } else { DUSK };
+        if sun.y >= 0.0 {
+            twilight.lerp(&DAY, (sun.y / DAY_ELEVATION).min(1.0))
+        } else {
+            twilight.lerp(&NIGHT, (-sun.y / NIGHT_ELEVATION).min(1.0))
+        }
+    }
 
-                            r = (r as f32 * brightness) as u8;
-                            g = (g as f32 * brightness) as u8;
-                            b = (b as f32 * brightness) as u8;
-                        }
-                    }
-                }
-                buffer.push(BltPixel::new(r, g, b));
-            }
+    fn lerp(&self, to: &Self, t: f32) -> Self {
+        let mix = |a: f32, b: f32| a + (b - a) * t;
+        Self {
+            zenith: self.zenith.lerp(to.zenith, t),
+            horizon: self.horizon.lerp(to.horizon, t),
+            glow: self.glow.lerp(to.glow, t),
+            ambient: mix(self.ambient, to.ambient),
+            diffuse: mix(self.diffuse, to.diffuse),
+            clouds: mix(self.clouds, to.clouds),
         }
+    }
+}
 
-        // Draw tile
-        while DRAW_LOCK.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
-            spin_loop();
+/// Fixed camera looking over the world from outside its -X/-Z corner
+struct Camera {
+    pos: Vec3,
+    /// Camera space to world space
+    rot: Mat3,
+    inv_projection: Mat4,
+    view_proj: Mat4,
+    /// Pixels a block spans at distance 1
+    focal: f32,
+}
+
+impl Camera {
+    fn overview(ctx: &GameContext) -> Self {
+        let size = ctx.world.read(|world| world.size) as f32;
+        let half = size * 0.5;
+        let pos = vec3(-size * 0.125, 32.0, -size * 0.125);
+        let target = vec3(half, 8.0, half);
+        let up = vec3(0.0, 1.0, 0.0);
+
+        let view = Mat4::look_at_rh(pos, target, up);
+        let screen = ctx.frames.size();
+        let projection = projection(screen, fov_y());
+        Self {
+            pos,
+            rot: Mat3::from_mat4(view).transpose(),
+            inv_projection: projection.inverse(),
+            view_proj: projection * view,
+            focal: projection.y_axis.y * screen.1 as f32 * 0.5,
         }
+    }
 
-        let _ = ctx.scr.gop.blt(uefi::proto::console::gop::BltOp::BufferToVideo {
-            buffer: &buffer,
-            src: BltRegion::Full,
-            dest: (start_x, start_y),
-            dims: (tile_w, tile_h),
-        });
+    /// Normalized direction through a point in NDC (-1..1, y up)
+    fn ray(&self, ndc: Vec2) -> Vec3 {
+        self.rot * camera_dir(&self.inv_projection, ndc)
+    }
+}
 
-        DRAW_LOCK.store(false, Ordering::Release);
+/// Camera-space ray directions, one per cell, shared by all cores. They only depend on the
+/// resolution, cell size and FOV, so each ray costs one rotation instead of an unprojection
+/// and a normalize.
+pub struct RayTable {
+    cells_x: usize,
+    dirs: Vec<Vec3>,
+}
+
+impl RayTable {
+    /// Has to be rebuilt when any of the inputs change, which for now they don't during a run
+    pub fn new((width, height): (usize, usize), scale: usize, fov_y: f32) -> Self {
+        let inv_projection = projection((width, height), fov_y).inverse();
+        let cells_x = width.div_ceil(scale);
+        let dirs = (0..height.div_ceil(scale))
+            .flat_map(|row| (0..cells_x).map(move |col| (col * scale, row * scale)))
+            .map(|pixel| camera_dir(&inv_projection, ndc(pixel, (width, height))))
+            .collect();
+        Self { cells_x, dirs }
     }
 
-    // Wait loop
-    loop {
-        if PANIC_STATE.load(Ordering::Acquire) { break; }
-        spin_loop()
+    /// Direction of the cell with its top-left corner at screen pixel `(x, y)`
+    fn get(&self, (x, y): (usize, usize), scale: usize) -> Vec3 {
+        self.dirs[y / scale * self.cells_x + x / scale]
     }
-    OK
 }
 
-fn ray_aabb_intersect(origin: Vec3, dir: Vec3, min: Vec3, max: Vec3) -> Option<f32> {
-    let t1 = (min.x - origin.x) / dir.x;
-    let t2 = (max.x - origin.x) / dir.x;
-    let t3 = (min.y - origin.y) / dir.y;
-    let t4 = (max.y - origin.y) / dir.y;
-    let t5 = (min.z - origin.z) / dir.z;
-    let t6 = (max.z - origin.z) / dir.z;
+fn projection((width, height): (usize, usize), fov_y: f32) -> Mat4 {
+    // Square pixels assumed, so the mode's aspect is the display's
+    Mat4::perspective_rh(fov_y.to_radians(), width as f32 / height as f32, 0.1, 100.0)
+}
+
+/// Screen pixel to NDC, y up
+fn ndc((x, y): (usize, usize), (width, height): (usize, usize)) -> Vec2 {
+    Vec2::new((x as f32 / width as f32) * 2.0 - 1.0, 1.0 - (y as f32 / height as f32) * 2.0)
+}
 
-    let tmin = t1.min(t2).max(t3.min(t4)).max(t5.min(t6));
-    let tmax = t1.max(t2).min(t3.max(t4)).min(t5.max(t6));
+/// Normalized camera-space direction through `ndc`
+fn camera_dir(inv_projection: &Mat4, ndc: Vec2) -> Vec3 {
+    inv_projection.project_point3(ndc.extend(1.0)).normalize()
+}
 
-    if tmax < 0.0 {
-        return None;
+/// Finds the block under the crosshair for the next frame's outline. Core 0 calls it before
+/// the render cores start so they all outline the same block.
+pub fn publish_target(ctx: &GameContext) {
+    let camera = Camera::overview(ctx);
+    let hit = ctx.world.read(|world| world.raycast(camera.pos, camera.ray(Vec2::ZERO), TARGET_REACH));
+    if let Some(hit) = hit {
+        for (axis, v) in TARGET.iter().zip(hit.pos.to_array()) {
+            axis.store(v, Ordering::Relaxed);
+        }
     }
+    TARGET_SET.store(hit.is_some(), Ordering::Release);
+}
 
-    if tmin > tmax {
-        return None;
+/// Where the camera of the last frame started was, `None` before the first frame
+pub fn player_pos() -> Option<Vec3> {
+    PLAYER_POS_SET.load(Ordering::Acquire)
+        .then(|| Vec3::from_array(PLAYER_POS.each_ref().map(|axis| f32::from_bits(axis.load(Ordering::Relaxed)))))
+}
+
+fn target() -> Option<IVec3> {
+    TARGET_SET.load(Ordering::Acquire)
+        .then(|| IVec3::from_array(TARGET.each_ref().map(|axis| axis.load(Ordering::Relaxed))))
+}
+
+/// Brings the heightmap snapshot and the minimap up to date with edits since the last frame.
+/// Core 0 calls it before the render cores start, they only read both.
+pub fn refresh_surface(ctx: &mut GameContext) {
+    let Some((min, max)) = ctx.world.write(World::take_dirty_columns) else { return };
+    ctx.world.read(|world| ctx.heightmap.refresh(world, min, max));
+    ctx.minimap.invalidate(&ctx.heightmap, min, max);
+}
+
+/// Sets `RenderSettings::underwater` for the next frame from the block at the camera. Core 0
+/// calls it before the render cores start so they all fog and tint the frame alike.
+pub fn publish_underwater(ctx: &mut GameContext) {
+    // Blocks are centred on their integer position, as for the minimap
+    let eye = (Camera::overview(ctx).pos + Vec3::splat(0.5)).floor().as_ivec3();
+    ctx.render.underwater = ctx.world.read(|world| world.get_block(eye).id == WATER);
+}
+
+/// Starts a new frame, advancing anything animated. Core 0 calls it before the render cores start.
+pub fn advance_frame() {
+    FRAME.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Moves the sun to fraction `t` of the day, wrapped into 0..1: 0 midnight, 0.25 sunrise,
+/// 0.5 noon, 0.75 sunset. The time stays where it is set, nothing advances it.
+pub fn set_time_of_day(t: f32) {
+    let t = t - Vec2::splat(t).floor().x;
+    // Sunrise at angle 0, a full turn per day
+    let arc = Vec2::from_angle((t - 0.25) * core::f32::consts::TAU);
+    set_sun_dir(vec3(arc.x, arc.y, SUN_TILT));
+}
+
+/// Current direction towards the sun
+pub fn sun_dir() -> Vec3 {
+    Vec3::from_array(SUN_DIR.each_ref().map(|axis| f32::from_bits(axis.load(Ordering::Relaxed))))
+}
+
+/// Points the sun along `dir` (towards it, any length) from the next frame on. Meant for core 0
+/// between frames; a zero vector is ignored.
+pub fn set_sun_dir(dir: Vec3) {
+    let Some(dir) = dir.try_normalize() else { return };
+    for (axis, v) in SUN_DIR.iter().zip(dir.to_array()) {
+        axis.store(v.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Current vertical field of view in degrees
+pub fn fov_y() -> f32 {
+    f32::from_bits(FOV_Y.load(Ordering::Relaxed))
+}
+
+/// Sets the vertical field of view, clamped to `FOV_Y_MIN..=FOV_Y_MAX`. Takes effect next frame.
+pub fn set_fov_y(degrees: f32) {
+    FOV_Y.store(degrees.clamp(FOV_Y_MIN, FOV_Y_MAX).to_bits(), Ordering::Relaxed);
+}
+
+/// Colour seen along one primary ray in `frame`, and the distance to what it hit (infinite for sky)
+fn shade(world: &World, origin: Vec3, dir: Vec3, frame: &Frame, ao: &mut AoCache) -> (Vec3, f32) {
+    let (sun, sky) = (frame.sun, &frame.sky);
+    let mut color = sky_color(dir, sun, sky);
+
+    let reach = (frame.settings.fog_end - frame.path).min(frame.reach);
+    let hit = world.raycast(origin, dir, reach);
+    if let Some(hit) = hit {
+        let def = block_def(hit.block.id);
+        // Hit point relative to the block centre, each axis in -0.5..0.5
+        let local = origin + dir * hit.dist - hit.pos.as_vec3();
+        color = if hit.normal == IVec3::ZERO {
+            // Camera inside the block: no face to light, just a flat dark fill
+            def.color * INSIDE_BLOCK_SHADE
+        } else {
+            // Lambert term from the sun over a flat ambient
+            let mut diffuse = hit.normal.as_vec3().dot(sun).max(0.0);
+            if frame.high_quality && diffuse > 0.0 {
+                let from = hit.pos.as_vec3() + local + hit.normal.as_vec3() * SHADOW_BIAS;
+                if world.raycast(from, sun, SHADOW_DIST).is_some_and(|h| h.pos != hit.pos) {
+                    diffuse = 0.0;
+                }
+            }
+            let brightness = (sky.ambient + sky.diffuse * diffuse).clamp(SUN_MIN, 1.0);
+
+            // Light of the air in front of the face, so caves go dark
+            let front = hit.pos + hit.normal;
+            let light = world.light_at(front) as f32 / MAX_LIGHT as f32;
+            let sky = if world.sky_light(front.x, front.z, front.y) { 1.0 } else { SKY_OCCLUDED };
+            let grain = if def.has_meta && hit.normal.abs() == log_axis(world.get_meta(hit.pos)) {
+                LOG_END_GRAIN
+            } else { 1.0 };
+            let (u, v) = face_uv(local, hit.normal);
+            let occlusion = ao.factor(world, hit.pos, hit.normal, u, v);
+            sample_texture(hit.block.id, face_index(hit.normal), u, v) * brightness * light * sky * grain * occlusion
+        };
+
+        // Near the edges of the face, in the two axes across it
+        let edge = local.abs().cmpgt(Vec3::splat(0.5 - OUTLINE_WIDTH)) & hit.normal.cmpeq(IVec3::ZERO);
+        if frame.target == Some(hit.pos) && hit.normal != IVec3::ZERO && edge.any() {
+            color = color.lerp(Vec3::ONE, OUTLINE_MIX);
+        }
+    }
+
+    // Only where the layer is in front of whatever the ray hit, rays going down never reach it
+    let cloud_dist = (CLOUD_Y - origin.y) / dir.y;
+    if let Some(drift) = frame.clouds.filter(|_| cloud_dist > 0.0 && hit.is_none_or(|h| h.dist > cloud_dist)) {
+        let noise = cloud_noise(origin + dir * cloud_dist, drift);
+        let cover = ((noise - CLOUD_THRESHOLD) / CLOUD_SOFT).clamp(0.0, 1.0);
+        let fade = (1.0 - cloud_dist / CLOUD_FADE_DIST).max(0.0);
+        // Thick parts of the layer are greyer underneath
+        let thickness = ((noise - CLOUD_THRESHOLD) / (1.0 - CLOUD_THRESHOLD)).clamp(0.0, 1.0);
+        let cloud = CLOUD_COLOR.lerp(CLOUD_SHADE, thickness) * sky.clouds;
+        color = color.lerp(cloud, cover * fade);
+    }
+
+    // Water doesn't stop the ray, it tints whatever is behind it by the distance travelled in it
+    let max_dist = hit.map_or(reach, |h| h.dist);
+    let crossings = world.crossings::<MAX_CROSSINGS>(origin, dir, max_dist);
+    let depth: f32 = crossings.iter().filter(|c| c.block.id == WATER).map(|c| c.far - c.near).sum();
+    if depth > 0.0 {
+        let tint = (WATER_TINT_MIN + depth * WATER_TINT_PER_BLOCK).min(WATER_TINT_MAX);
+        color = color.lerp(block_def(WATER).color, tint);
+    }
+    // Coming down through the top of the water, not through a block of water below more of it
+    let surface = crossings.iter().find(|c| c.block.id == WATER)
+        .filter(|c| c.normal == IVec3::Y && world.get_block(c.pos + IVec3::Y).id != WATER);
+    if let Some(surface) = surface {
+        color = if frame.high_quality {
+            // Mirror around +Y, more of it at grazing angles (Schlick)
+            let from = origin + dir * surface.near + Vec3::Y * SHADOW_BIAS;
+            let bounce = frame.bounce(surface.near, REFLECT_DIST);
+            let (reflected, _) = shade(world, from, dir * vec3(1.0, -1.0, 1.0), &bounce, &mut AoCache::default());
+            let grazing = 1.0 - (-dir.y).clamp(0.0, 1.0);
+            let fresnel = WATER_F0 + (1.0 - WATER_F0) * grazing * grazing * grazing * grazing * grazing;
+            color.lerp(reflected, fresnel)
+        } else {
+            color.lerp(Vec3::ONE, WATER_SURFACE_HIGHLIGHT)
+        };
+    }
+    // Glass tints once per pane, connected blocks don't stack
+    let mut prev: Option<&Crossing> = None;
+    for c in crossings.iter() {
+        let continued = prev.is_some_and(|p| p.block.id == GLASS && (c.near - p.far).abs() < GLASS_JOIN);
+        if c.block.id == GLASS && !continued {
+            color = color.lerp(block_def(GLASS).color, GLASS_TINT);
+        }
+        prev = Some(c);
     }
 
-    Some(tmin)
+    // Geometry fades into the horizon haze, the sky already is it. Under water the sky is
+    // past the view distance too, so everything fades into the murk.
+    if frame.settings.underwater {
+        let murk = UNDERWATER_FOG * (sky.ambient + sky.diffuse).clamp(SUN_MIN, 1.0);
+        color = color.lerp(murk, frame.settings.fog(frame.path + hit.map_or(f32::INFINITY, |h| h.dist)));
+    } else if let Some(hit) = hit {
+        color = color.lerp(sky.horizon, frame.settings.fog(frame.path + hit.dist));
+    }
+    (color, hit.map_or(f32::INFINITY, |h| h.dist))
+}
+
+/// What a debug `RenderMode` shows along one primary ray, and the hit distance like `shade`
+fn debug_view(world: &World, origin: Vec3, dir: Vec3, frame: &Frame) -> (Vec3, f32) {
+    let reach = frame.settings.fog_end;
+    let dist = world.raycast(origin, dir, reach).map_or(f32::INFINITY, |h| h.dist);
+    let color = match frame.settings.mode {
+        RenderMode::Heatmap => {
+            let t = world.ray_tests(origin, dir, reach) as f32 / frame.tests_per_pass.max(1) as f32;
+            match t {
+                t if t > 1.0 => Vec3::ONE,
+                t if t < 0.5 => HEAT_COLD.lerp(HEAT_WARM, t * 2.0),
+                t => HEAT_WARM.lerp(HEAT_HOT, t * 2.0 - 1.0),
+            }
+        }
+        RenderMode::Normal | RenderMode::Depth => Vec3::splat(1.0 - (dist / reach).min(1.0)),
+    };
+    (color, dist)
+}
+
+/// Sky along `dir`: horizon to zenith gradient, warmer low towards the sun's azimuth, then the sun disk
+fn sky_color(dir: Vec3, sun: Vec3, sky: &SkyPalette) -> Vec3 {
+    let mut color = sky.horizon.lerp(sky.zenith, dir.y.max(0.0));
+    if let (Some(flat), Some(sun_flat)) = (dir.with_y(0.0).try_normalize(), sun.with_y(0.0).try_normalize()) {
+        let glow = flat.dot(sun_flat).max(0.0) * (1.0 - dir.y.abs());
+        color = color.lerp(sky.glow, glow * SUN_GLOW_MIX);
+    }
+    let facing = dir.dot(sun);
+    if facing > SUN_HALO {
+        let t = ((facing - SUN_HALO) / (SUN_DISK - SUN_HALO)).min(1.0);
+        color = color.lerp(SUN_COLOR, t * t);
+    }
+    color
+}
+
+/// 0..1 noise of the cloud layer at `p` on its plane, shifted `drift` blocks along +X
+fn cloud_noise(p: Vec3, drift: f32) -> f32 {
+    let x = ((p.x - drift) * CLOUD_RES) as i32;
+    let z = (p.z * CLOUD_RES) as i32;
+    value_noise(CLOUD_SEED, x, z, CLOUD_CELL * CLOUD_RES as i32)
+}
+
+/// Position on the face with outward `normal` of a hit point given relative to the block
+/// centre, as a 0..1 UV with v running up the sides
+fn face_uv(local: Vec3, normal: IVec3) -> (f32, f32) {
+    let (u, v) = face_axes(normal);
+    (local.dot(u.as_vec3()) + 0.5, local.dot(v.as_vec3()) + 0.5)
+}
+
+/// Unit axes along which `face_uv`'s u and v grow on the face with outward `normal`
+fn face_axes(normal: IVec3) -> (IVec3, IVec3) {
+    if normal.x != 0 { (IVec3::Z, IVec3::Y) }
+    else if normal.y != 0 { (IVec3::X, IVec3::Z) }
+    else { (IVec3::X, IVec3::Y) }
+}
+
+/// Opaque neighbours in front of the last face shaded, neighbouring cells of a row mostly
+/// hit the same face
+#[derive(Default)]
+struct AoCache {
+    face: Option<(IVec3, IVec3)>,
+    /// Bit i set when `AO_OFFSETS[i]` is opaque
+    mask: u8,
+}
+
+impl AoCache {
+    /// Brightness factor at `(u, v)` on the face of `pos` with outward `normal`, from the
+    /// usual per-corner voxel AO blended across the face
+    fn factor(&mut self, world: &impl BlockSource, pos: IVec3, normal: IVec3, u: f32, v: f32) -> f32 {
+        let (tu, tv) = face_axes(normal);
+        if self.face != Some((pos, normal)) {
+            let front = pos + normal;
+            self.face = Some((pos, normal));
+            self.mask = AO_OFFSETS.iter().enumerate()
+                .filter(|(_, (du, dv))| world.is_opaque(front + tu * *du + tv * *dv))
+                .fold(0, |mask, (i, _)| mask | 1 << i);
+        }
+        let occluded = |du: i32, dv: i32| {
+            let i = AO_OFFSETS.iter().position(|&o| o == (du, dv)).unwrap_or(0);
+            (self.mask >> i) & 1 != 0
+        };
+        // 0 when both sides are blocked, otherwise one step darker per occluder
+        let corner = |cu: i32, cv: i32| {
+            let (side_u, side_v, diag) = (occluded(cu, 0), occluded(0, cv), occluded(cu, cv));
+            let open = if side_u && side_v { 0 } else { 3 - side_u as i32 - side_v as i32 - diag as i32 };
+            open as f32 / 3.0
+        };
+        let bottom = corner(-1, -1) + (corner(1, -1) - corner(-1, -1)) * u;
+        let top = corner(-1, 1) + (corner(1, 1) - corner(-1, 1)) * u;
+        let open = bottom + (top - bottom) * v;
+        1.0 - AO_STRENGTH * (1.0 - open)
+    }
 }
 
 /// 之后再写 只访问全局变量
@@ -1,34 +1,69 @@
-#![no_main]
-#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)]
 
 mod ascii_font;
+mod bmp;
 mod error;
 mod game;
 mod render;
+mod time;
 mod world;
 
 extern crate alloc;
 
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 use core::ffi::c_void;
 use core::ptr::addr_of_mut;
+use core::sync::atomic::Ordering;
 use core::time::Duration;
 use uefi::boot::{
     create_event, get_handle_for_protocol, open_protocol_exclusive, set_watchdog_timer, EventType,
     Tpl,
 };
 use uefi::prelude::*;
+use uefi::proto::loaded_image::LoadedImage;
 use uefi::proto::pi::mp::MpServices;
+use uefi::proto::console::gop::BltPixel;
+use uefi::CStr16;
+use glam::{IVec3, Vec3};
 
 use crate::error::{kernel_panic, OK, Result};
-use crate::game::{game_task, GameContext};
-use crate::render::Screen;
-use crate::world::World;
+use crate::game::{game_task, FogMode, GameContext, RayTable, RenderMode, RenderSettings, Renderer, WorkSplit};
+use crate::render::{Billboard, ColorGrade, FrameBuffers, Heightmap, Minimap, ModePreference, Screen};
+use crate::world::{block_def, World, WorldBounds, WorldBuilder, WorldKind, WorldLock, WrapMode, SEA_LEVEL, WORLD_HEIGHT};
+
+/// 固定种子用于复现世界，None 则每次开机随机
+const FIXED_SEED: Option<u64> = None;
+const TREE_DENSITY: f32 = 0.01;
+const WORLD_SIZE: i32 = world::DEFAULT_WORLD_SIZE;
+/// 该目录下的 <名字>.bin (结构) 或 <名字>.rle (区域) 会被放到对应的原点，没列出的文件忽略
+const STRUCTURE_DIR: &CStr16 = cstr16!("\\ueficraft\\structures");
+const STRUCTURE_ORIGINS: &[(&str, IVec3)] = &[
+    ("castle", IVec3::new(40, 12, 40)),
+    ("test_scene", IVec3::new(8, 12, 8)),
+];
+/// 出生点附近找空地放小屋的搜索半径
+const HOUSE_SEARCH_RADIUS: i32 = 12;
+/// 存档存在时开机直接读取，删掉即重新生成；写入时旁边会留 .tmp/.bak
+const SAVE_PATH: &CStr16 = cstr16!("\\ueficraft\\world.dat");
+/// `exportrle` 启动参数把整个世界导出到这里
+const RLE_EXPORT_PATH: &CStr16 = cstr16!("\\ueficraft\\export.rle");
+/// 出生点标记浮在出生点上方多高、什么颜色
+const SPAWN_MARKER_LIFT: Vec3 = Vec3::new(0.0, 1.5, 0.0);
+const SPAWN_MARKER_COLOR: BltPixel = BltPixel::new(255, 220, 40);
+/// `ssaa` 启动参数下每个屏幕像素在每个方向上渲染几次
+const SUPERSAMPLE: usize = 2;
+const GAMMA_MIN: f32 = 0.5;
+const GAMMA_MAX: f32 = 3.0;
+const BRIGHTNESS_MAX: f32 = 2.0;
 
 #[entry]
 fn main() -> Status {
     uefi::helpers::init().expect("Failed to init UEFI");
 
-    let mut scr = Screen::new().expect("Failed to init screen");
+    let mut scr = Screen::new_with_preference(mode_preference_from_load_options()).expect("Failed to init screen");
     if let Err(e) = init(&mut scr) {
         kernel_panic(&mut scr, e);
     }
@@ -46,24 +81,257 @@ fn init(scr: &mut Screen) -> Result {
     let mp = t!(open_protocol_exclusive::<MpServices>(mp));
     let num_cores = t!(mp.get_number_of_processors()).enabled;
 
-    let mut world = World::new();
-    world.generate_example();
+    // 帧计时用的 TSC 时钟，对着固件的 stall 校准一次
+    let tsc_per_ms = time::calibrate();
+    scr.println(&format!("TSC: {} MHz", tsc_per_ms / 1000))?;
+
+    // 画面全走 blt(BufferToVideo)，像素格式由固件转换，这里只记一下选中的模式
+    let mode = scr.gop.current_mode_info();
+    scr.println(&format!("Video: {:?} {:?} via Blt", mode.resolution(), mode.pixel_format()))?;
+
+    // 启动参数里显式指定了世界类型就重新生成，否则优先读档
+    let seed = FIXED_SEED.unwrap_or_else(world::random_seed);
+    let requested = world_kind_from_load_options(seed);
+    let saved = match requested {
+        Some(_) => None,
+        None => World::load_from_file(SAVE_PATH, scr)?,
+    };
+
+    let world = match saved {
+        Some(world) => {
+            scr.println(&format!("Loaded {} blocks (save #{})", world.blocks.len(), world.save_count))?;
+            world
+        }
+        None => {
+            let kind = requested.unwrap_or(WorldKind::Terrain { seed });
+            scr.println(&format!("World seed: {:#018x}", seed))?;
+
+            let mut world = WorldBuilder::new(kind)
+                .size(WORLD_SIZE)
+                .seed(seed)
+                .sea_level(load_option_value("sea").and_then(|v| v.parse().ok()).unwrap_or(SEA_LEVEL).clamp(0, WORLD_HEIGHT - 1))
+                .ores(!has_load_option("noores"))
+                .house((!has_load_option("nohouse")).then_some(HOUSE_SEARCH_RADIUS))
+                .trees((!has_load_option("notrees")).then_some(TREE_DENSITY))
+                .structures(STRUCTURE_DIR, STRUCTURE_ORIGINS)
+                .build(scr)?;
+
+            world.save_to_file(SAVE_PATH)?;
+            world
+        }
+    };
+    t!(world.validate(), "world failed validation after init");
+
+    // 世界从这里交给锁，之后的修改一律走 WorldLock::write，和渲染核运行时的规矩一样
+    let world = WorldLock::new(world);
+    world.write(|world| -> Result {
+        if has_load_option("wrap") {
+            world.wrap = WrapMode::XZ;
+        }
+        // open 去掉 y<0 的基岩，世界外全是空气，飞行测试用
+        if has_load_option("open") {
+            world.bounds = WorldBounds::Open;
+        }
+        // exportrle 导出整个世界，离线改完放进 STRUCTURE_DIR 并在 STRUCTURE_ORIGINS 登记名字即可放回
+        if has_load_option("exportrle") {
+            world.export_rle_to_file(RLE_EXPORT_PATH)?;
+            scr.println(&format!("Exported world to {}", RLE_EXPORT_PATH))?;
+        }
+        scr.progress("Lighting", 0, 1)?;
+        world.relight();
+        scr.progress("Lighting", 1, 1)?;
+        // 第一帧之前把进度条擦掉
+        scr.clear_progress()?;
+
+        let reclaimed = world.compact();
+        scr.println(&format!("Compacted block list, {} KiB reclaimed", reclaimed / 1024))?;
+        OK
+    })?;
+    world.read(|world| -> Result {
+        let stats = world.stats();
+        scr.println(&format!(
+            "Solid blocks: {} | entries: {} | {} KiB used / {} KiB reserved | max y: {:?}",
+            world.count_blocks(), stats.entries, stats.bytes_used / 1024, stats.bytes_reserved / 1024, stats.max_y
+        ))?;
+        scr.println(&format!("Spawn point: {}", world.spawn_point()))?;
+        let census = world.census();
+        let kinds: Vec<String> = (0..=u8::MAX)
+            .filter(|&id| census[id as usize] > 0)
+            .map(|id| format!("{} {}", block_def(id).name, census[id as usize]))
+            .collect();
+        scr.println(&format!("Census: {}", kinds.join(", ")))
+    })?;
+
+    // scale=N 时每 N×N 像素只算一条光线，慢机器上用
+    let pixel_scale = load_option_value("scale")
+        .and_then(|v| v.parse::<usize>().ok())
+        .map_or(1, |n| n.clamp(1, game::TILE_SIZE).next_power_of_two());
+    scr.println(&format!("Render scale: 1/{}", pixel_scale))?;
+    if let Some(fov) = load_option_value("fov").and_then(|v| v.parse().ok()) {
+        game::set_fov_y(fov);
+    }
+    scr.println(&format!("FOV: {} deg", game::fov_y()))?;
+    game::CINEMATIC.store(has_load_option("cinematic"), Ordering::Relaxed);
+    game::HIGH_QUALITY.store(has_load_option("hq"), Ordering::Relaxed);
+    game::CLOUDS.store(!has_load_option("noclouds"), Ordering::Relaxed);
+    game::MINIMAP.store(has_load_option("minimap"), Ordering::Relaxed);
+    // debug 显示引擎内部状态，cleanshot 截图时不带它
+    game::DEBUG_OVERLAY.store(has_load_option("debug"), Ordering::Relaxed);
+    game::CLEAN_SHOT.store(has_load_option("cleanshot"), Ordering::Relaxed);
+    // 没有键盘输入，`screenshot` 代替 F2 截下第一帧
+    game::SCREENSHOT.store(has_load_option("screenshot"), Ordering::Relaxed);
+    if let Some(time) = load_option_value("time").and_then(|v| v.parse().ok()) {
+        game::set_time_of_day(time);
+    }
+
+    // view=N 调视距，fog=linear/exp/exp2 选雾的衰减方式，split=slabs/rows/tiles 选多核分工方式，nodither 关掉抖动
+    let mut render = RenderSettings::default();
+    if let Some(dist) = load_option_value("view").and_then(|v| v.parse().ok()) {
+        render.set_view_distance(dist);
+    }
+    match load_option_value("fog").as_deref() {
+        Some("exp") => render.fog_mode = FogMode::Exp,
+        Some("exp2") => render.fog_mode = FogMode::Exp2,
+        _ => {}
+    }
+    match load_option_value("split").as_deref() {
+        Some("slabs") => render.work_split = WorkSplit::ContiguousSlabs,
+        Some("rows") => render.work_split = WorkSplit::InterleavedRows,
+        _ => {}
+    }
+    render.dither = !has_load_option("nodither");
+    // heightmap 换成只看每列顶面的快速渲染，看不到洞穴
+    if has_load_option("heightmap") {
+        render.renderer = Renderer::Heightmap;
+    }
+    scr.println(&format!("Renderer: {:?}", render.renderer))?;
+    // mode=heat 按每条光线的方块测试次数上色，mode=depth 按命中距离显示灰度
+    render.mode = match load_option_value("mode").as_deref() {
+        Some("heat") => RenderMode::Heatmap,
+        Some("depth") => RenderMode::Depth,
+        _ => RenderMode::Normal,
+    };
+    if render.mode != RenderMode::Normal {
+        scr.println(&format!("Debug view: {:?}", render.mode))?;
+    }
+    // grade=vga/sepia/contrast 给整帧 (连同 HUD) 调色
+    render.grade = match load_option_value("grade").as_deref() {
+        Some("vga") => ColorGrade::Vga,
+        Some("sepia") => ColorGrade::Sepia,
+        Some("contrast") => ColorGrade::HighContrast,
+        _ => ColorGrade::None,
+    };
+    if render.grade != ColorGrade::None {
+        scr.println(&format!("Colour grade: {:?}", render.grade))?;
+    }
+    scr.println(&format!("View distance: {} ({:?} fog)", render.fog_end, render.fog_mode))?;
+    scr.println(&format!("Work split: {:?}", render.work_split))?;
+
+    // 出生点上方插个标记
+    let spawn = world.read(World::spawn_point);
+    let billboards = alloc::vec![Billboard { pos: spawn + SPAWN_MARKER_LIFT, color: SPAWN_MARKER_COLOR, size: 0.5 }];
+
+    // ssaa 时按 2 倍分辨率渲染再缩小，四倍光线
+    let supersample = if has_load_option("ssaa") { SUPERSAMPLE } else { 1 };
+    let mut frames = FrameBuffers::new(mode.resolution(), supersample);
+    // gamma=2.2 提亮暗部，brightness=1.2 整体调亮，按屏幕调
+    let gamma = load_option_value("gamma")
+        .and_then(|v| v.parse().ok())
+        .map_or(1.0, |g: f32| g.clamp(GAMMA_MIN, GAMMA_MAX));
+    let brightness = load_option_value("brightness")
+        .and_then(|v| v.parse().ok())
+        .map_or(1.0, |b: f32| b.clamp(0.0, BRIGHTNESS_MAX));
+    frames.set_tone(gamma, brightness);
+    scr.println(&format!("Gamma {} brightness {}", gamma, brightness))?;
+    let rays = RayTable::new(frames.size(), pixel_scale, game::fov_y());
+    scr.println(&format!("Supersampling: {}x{}", supersample, supersample))?;
 
+    // 快照本身就是最新的，之前生成和加载留下的脏列不用再刷一遍
+    let heightmap = world.write(|world| {
+        world.take_dirty_columns();
+        Heightmap::new(world)
+    });
     let mut ctx = GameContext {
         mp: &mp,
         scr,
         num_cores,
         world,
+        pixel_scale,
+        render,
+        frames,
+        billboards,
+        rays,
+        heightmap,
+        minimap: Minimap::new(),
+        bsp: t!(mp.who_am_i()),
     };
+    game::advance_frame();
+    game::refresh_surface(&mut ctx);
+    game::publish_target(&ctx);
+    game::publish_underwater(&mut ctx);
     let arg_ptr = addr_of_mut!(ctx).cast::<c_void>();
 
     let event = unsafe { t!(create_event(EventType::empty(), Tpl::CALLBACK, None, None)) };
 
-    if num_cores > 1 {
-        let _ = mp.startup_all_aps(false, game_task, arg_ptr, Some(event), None);
+    if num_cores > 1 && let Err(e) = mp.startup_all_aps(false, game_task, arg_ptr, Some(event), None) {
+        // AP 没起来就只剩 BSP，所有分片都由它自己画，不然帧屏障会一直等下去
+        ctx.scr.warn(&format!("Failed to start APs ({:?}), rendering on the BSP only", e.status()))?;
+        ctx.num_cores = 1;
     }
 
     game_task(arg_ptr);
 
     OK
+}
+
+/// 从启动参数选世界类型: flat[=高度] / terrain[=种子] / grid[=间隔]
+/// 例如 UEFI Shell 里 `ueficraft.efi grid=8`，再加 `wrap` 让世界在 X/Z 上首尾相接，`open` 去掉脚下的基岩，
+/// `noores` / `nohouse` / `notrees` 跳过对应的生成步骤，`sea=8` 改海平面高度，`cinematic` 隐藏准星，
+/// `hq` 打开阴影和水面反射，`noclouds` 关掉云层，`minimap` 在右上角显示俯视小地图，`time=0.5` 设定一天中的时刻 (0 午夜，0.25 日出，0.5 正午)
+fn world_kind_from_load_options(seed: u64) -> Option<WorldKind> {
+    load_options()?.split_whitespace().find_map(|arg| {
+        let (name, value) = arg.split_once('=').map_or((arg, None), |(n, v)| (n, Some(v)));
+        let int = |default: i32| value.and_then(|v| v.parse().ok()).unwrap_or(default);
+        match name {
+            "flat" => Some(WorldKind::Flat { height: int(4) }),
+            "terrain" => Some(WorldKind::Terrain { seed: value.and_then(parse_seed).unwrap_or(seed) }),
+            "grid" => Some(WorldKind::DebugGrid { spacing: int(8) }),
+            _ => None,
+        }
+    })
+}
+
+/// 启动参数里有没有单独的 `name` 开关
+fn has_load_option(name: &str) -> bool {
+    load_options().is_some_and(|options| options.split_whitespace().any(|arg| arg == name))
+}
+
+/// 从启动参数选显示模式: `video=1920x1080` 指定分辨率 (没有就用最大的)，
+/// `video=current` 保持固件留下的模式，默认切到最大的模式
+fn mode_preference_from_load_options() -> ModePreference {
+    match load_option_value("video").as_deref() {
+        Some("current") => ModePreference::Current,
+        Some(size) => size.split_once('x')
+            .and_then(|(w, h)| Some(ModePreference::Exact(w.parse().ok()?, h.parse().ok()?)))
+            .unwrap_or(ModePreference::Highest),
+        None => ModePreference::Highest,
+    }
+}
+
+/// 启动参数里 `name=值` 的值
+fn load_option_value(name: &str) -> Option<String> {
+    load_options()?.split_whitespace()
+        .find_map(|arg| arg.strip_prefix(name)?.strip_prefix('=').map(ToString::to_string))
+}
+
+fn load_options() -> Option<String> {
+    let image = open_protocol_exclusive::<LoadedImage>(boot::image_handle()).ok()?;
+    Some(image.load_options_as_cstr16().ok()?.to_string())
+}
+
+fn parse_seed(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
 }
\ No newline at end of file
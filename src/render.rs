@@ -1,19 +1,114 @@
 use uefi::boot::{get_handle_for_protocol, open_protocol_exclusive, ScopedProtocol};
-use uefi::proto::console::gop::{BltOp, BltPixel, GraphicsOutput};
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::vec;
+use alloc::vec::Vec;
+use uefi::proto::console::gop::{BltOp, BltPixel, BltRegion, GraphicsOutput, Mode};
+use glam::{IVec2, IVec3, Mat4, Vec2, Vec3};
 use crate::ascii_font::FONT_8X16;
 use crate::error::{Result, OK};
 use crate::t;
+use crate::world::{block_def, rgb, World, AIR, BRICK, DIRT, GRASS, SAND, STONE, WOOD};
 
 pub struct Screen {
     pub gop: ScopedProtocol<GraphicsOutput>,
     row_ptr: usize,
+    /// The last `LOG_LINES` lines printed, oldest at `log_next`, for the panic screen
+    log: [LineBuf<LOG_LINE_LEN>; LOG_LINES],
+    log_next: usize,
 }
 
+/// Which video mode `Screen::new_with_preference` switches to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModePreference {
+    /// Whatever the firmware left active
+    Current,
+    /// The largest mode, usually the panel's native one
+    Highest,
+    /// This resolution if there is such a mode, otherwise `Highest`
+    Exact(usize, usize),
+}
+
+/// Lines `Screen` keeps of what it printed, and bytes kept of each
+const LOG_LINES: usize = 6;
+const LOG_LINE_LEN: usize = 96;
+const PANIC_BG: BltPixel = BltPixel::new(0, 0, 120);
+pub const PANIC_FG: BltPixel = BltPixel::new(255, 255, 255);
+/// Console text colours by what the line is about
+pub const LOG_NORMAL: BltPixel = BltPixel::new(190, 190, 190);
+pub const LOG_WARNING: BltPixel = BltPixel::new(255, 210, 60);
+pub const LOG_ERROR: BltPixel = BltPixel::new(255, 80, 80);
+
 impl Screen {
     pub fn new() -> Result<Self> {
         let gop = t!(get_handle_for_protocol::<GraphicsOutput>());
         let gop = t!(open_protocol_exclusive::<GraphicsOutput>(gop));
-        Ok(Self { gop, row_ptr: 0 })
+        Ok(Self { gop, row_ptr: 0, log: [LineBuf::default(); LOG_LINES], log_next: 0 })
+    }
+
+    /// `new`, then switches to the best mode for `pref`
+    pub fn new_with_preference(pref: ModePreference) -> Result<Self> {
+        let mut scr = Self::new()?;
+        scr.set_preferred_mode(pref);
+        Ok(scr)
+    }
+
+    /// Tries the modes in `pref`'s order until the firmware accepts one, keeping the current
+    /// mode if none is. Every pixel format will do, all drawing goes through Blt.
+    fn set_preferred_mode(&mut self, pref: ModePreference) {
+        let target = match pref {
+            ModePreference::Current => return,
+            ModePreference::Highest => None,
+            ModePreference::Exact(width, height) => Some((width, height)),
+        };
+        let mut modes: Vec<Mode> = self.gop.modes().collect();
+        // The exact match first, then largest first
+        modes.sort_by_key(|mode| {
+            let (width, height) = mode.info().resolution();
+            (Some((width, height)) != target, core::cmp::Reverse(width * height))
+        });
+        for mode in &modes {
+            if mode.info().resolution() == self.gop.current_mode_info().resolution() { break }
+            if self.gop.set_mode(mode).is_ok() { break }
+        }
+    }
+
+    /// Replaces the whole screen with `lines` in their colours and then the recent log, centred
+    /// on dark blue. Only uses the GOP and the stack, so it still works with the heap or frame buffers broken.
+    pub fn show_panic(&mut self, lines: &[(&str, BltPixel)]) {
+        let (width, height) = self.gop.current_mode_info().resolution();
+        let _ = self.gop.blt(BltOp::VideoFill { color: PANIC_BG, dest: (0, 0), dims: (width, height) });
+
+        let (log, next) = (self.log, self.log_next);
+        let recent = (0..LOG_LINES)
+            .map(|i| (log[(next + i) % LOG_LINES].as_str(), LOG_NORMAL))
+            .filter(|(l, _)| !l.is_empty());
+        let count = lines.len() + 2 + recent.clone().count();
+        let mut y = height.saturating_sub(count * 18) / 2;
+        for (line, fg) in lines.iter().copied().chain([("", PANIC_FG), ("Last messages:", PANIC_FG)]).chain(recent) {
+            let x = width.saturating_sub(line.chars().count() * 8) / 2;
+            self.blit_text((x, y), line, fg, PANIC_BG);
+            y += 18;
+        }
+    }
+
+    /// `text` at `(x, y)` one character per blit from a stack buffer, cut off at the screen edge
+    fn blit_text(&mut self, (x, y): (usize, usize), text: &str, fg: BltPixel, bg: BltPixel) {
+        let (width, height) = self.gop.current_mode_info().resolution();
+        if y + 16 > height { return }
+        for (i, c) in text.chars().enumerate() {
+            let gx = x + i * 8;
+            if gx + 8 > width { break }
+            let mut cell = [bg; 8 * 16];
+            draw_text(&mut cell, (gx, y), 8, (gx, y), c.encode_utf8(&mut [0; 4]), fg, TextStyle::Plain);
+            let _ = self.gop.blt(BltOp::BufferToVideo {
+                buffer: &cell,
+                src: BltRegion::Full,
+                dest: (gx, y),
+                dims: (8, 16),
+            });
+        }
     }
 
     pub fn clear(&mut self) -> Result {
@@ -29,14 +124,68 @@ impl Screen {
         OK
     }
 
+    /// Progress bar along the bottom of the screen for long boot steps: `stage` and the
+    /// percentage above a bar `done / total` filled. Drawn off screen and blitted in one go.
+    pub fn progress(&mut self, stage: &str, done: u32, total: u32) -> Result {
+        let (width, height) = self.gop.current_mode_info().resolution();
+        let rows = PROGRESS_TEXT + PROGRESS_GAP + PROGRESS_BAR;
+        if width <= 2 * PROGRESS_MARGIN || height < rows + PROGRESS_MARGIN { return OK }
+        let bar_w = width - 2 * PROGRESS_MARGIN;
+        let mut area = vec![BltPixel::new(0, 0, 0); bar_w * rows];
+
+        let fraction = if total == 0 { 1.0 } else { (done.min(total) as f32) / total as f32 };
+        let text = alloc::format!("{} {:3.0}%", stage, fraction * 100.0);
+        draw_text(&mut area, (0, 0), bar_w, (0, 0), &text, PROGRESS_COLOR, TextStyle::Plain);
+        let bar = &mut area[(PROGRESS_TEXT + PROGRESS_GAP) * bar_w..];
+        let filled = (bar_w as f32 * fraction) as usize;
+        for (i, p) in bar.iter_mut().enumerate() {
+            *p = if i % bar_w < filled { PROGRESS_COLOR } else { PROGRESS_TRACK };
+        }
+
+        t!(self.gop.blt(BltOp::BufferToVideo {
+            buffer: &area,
+            src: BltRegion::Full,
+            dest: (PROGRESS_MARGIN, height - PROGRESS_MARGIN - rows),
+            dims: (bar_w, rows),
+        }));
+        OK
+    }
+
+    /// Blanks the area `progress` draws in
+    pub fn clear_progress(&mut self) -> Result {
+        let (width, height) = self.gop.current_mode_info().resolution();
+        let rows = (PROGRESS_TEXT + PROGRESS_GAP + PROGRESS_BAR + PROGRESS_MARGIN).min(height);
+        t!(self.gop.blt(BltOp::VideoFill {
+            color: BltPixel::new(0, 0, 0),
+            dest: (0, height - rows),
+            dims: (width, rows),
+        }));
+        OK
+    }
+
+    /// `println_colored` in `LOG_NORMAL` on black
     pub fn println(&mut self, text: &str) -> Result {
+        self.println_colored(text, LOG_NORMAL, Some(BltPixel::new(0, 0, 0)))
+    }
+
+    /// `println_colored` in `LOG_WARNING` on black, for things skipped or worked around
+    pub fn warn(&mut self, text: &str) -> Result {
+        self.println_colored(text, LOG_WARNING, Some(BltPixel::new(0, 0, 0)))
+    }
+
+    /// Prints `text` as the next console line(s) in `fg`. A `bg` of `None` leaves whatever is
+    /// behind the glyphs as it is, only foreground pixels are written.
+    pub fn println_colored(&mut self, text: &str, fg: BltPixel, bg: Option<BltPixel>) -> Result {
         let mut x = 0;
         let (width, height) = self.gop.current_mode_info().resolution();
 
-        let fg = BltPixel::new(255, 255, 255);
-        let bg = BltPixel::new(0, 0, 0);
-
         if self.row_ptr + 20 >= height { self.row_ptr = 0 }
+        for line in text.lines() {
+            let mut kept = LineBuf::default();
+            let _ = kept.write_str(line);
+            self.log[self.log_next] = kept;
+            self.log_next = (self.log_next + 1) % LOG_LINES;
+        }
 
         for c in text.chars() {
             if c == '\n' {
@@ -55,11 +204,10 @@ impl Screen {
             let index = (c as usize) & 0x7F;
             let glyph = &FONT_8X16[index];
 
-            for row in 0..16 {
-                let row_bits = glyph[row];
+            for (row, &row_bits) in glyph.iter().enumerate() {
                 for col in 0..8 {
                     let is_fg = (row_bits >> (7 - col)) & 1 == 1;
-                    let color = if is_fg { fg } else { bg };
+                    let Some(color) = (if is_fg { Some(fg) } else { bg }) else { continue };
 
                     t!(self.gop.blt(BltOp::VideoFill {
                         color,
@@ -74,4 +222,615 @@ impl Screen {
 
         OK
     }
-}
\ No newline at end of file
+}
+
+/// Fixed-size text, so formatting a line doesn't allocate. Whatever doesn't fit is dropped.
+#[derive(Clone, Copy)]
+pub struct LineBuf<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Default for LineBuf<N> {
+    fn default() -> Self {
+        Self { bytes: [0; N], len: 0 }
+    }
+}
+
+impl<const N: usize> LineBuf<N> {
+    pub fn as_str(&self) -> &str {
+        // Only whole characters are ever written
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or_default()
+    }
+}
+
+impl<const N: usize> Write for LineBuf<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let end = self.len + c.len_utf8();
+            if end > N { break }
+            c.encode_utf8(&mut self.bytes[self.len..end]);
+            self.len = end;
+        }
+        Ok(())
+    }
+}
+
+/// Rows of the progress bar's stage line, the gap under it and the bar itself
+const PROGRESS_TEXT: usize = 16;
+const PROGRESS_GAP: usize = 4;
+const PROGRESS_BAR: usize = 8;
+/// Distance of the progress bar from the screen's sides and bottom
+const PROGRESS_MARGIN: usize = 16;
+const PROGRESS_COLOR: BltPixel = BltPixel::new(120, 200, 90);
+const PROGRESS_TRACK: BltPixel = BltPixel::new(40, 40, 40);
+
+/// Two full-screen buffers the render cores fill tile by tile. Only `present` touches the
+/// screen, so a frame is never shown half drawn.
+pub struct FrameBuffers {
+    /// Internal size, the screen's times `supersample`
+    width: usize,
+    height: usize,
+    buffers: [UnsafeCell<Target>; 2],
+    /// Index of the buffer being drawn into
+    back: AtomicUsize,
+    /// Rendered pixels per screen pixel along each axis
+    supersample: usize,
+    /// Box-filtered screen-sized frame when supersampling, empty otherwise
+    output: UnsafeCell<Vec<BltPixel>>,
+    /// Per-channel output level for each traced level, none while it would be the identity
+    tone: Option<[u8; 256]>,
+}
+
+/// One frame's pixels and the distance along each pixel's primary ray, infinite for sky
+struct Target {
+    pixels: Vec<BltPixel>,
+    depth: Vec<f32>,
+}
+
+// Cores only write disjoint tiles of the back buffer, and `present` runs once they are all done
+unsafe impl Sync for FrameBuffers {}
+
+impl FrameBuffers {
+    /// Buffers for a `screen` sized display, rendered at `supersample` times its resolution
+    pub fn new(screen: (usize, usize), supersample: usize) -> Self {
+        let (width, height) = (screen.0 * supersample, screen.1 * supersample);
+        let blank = || UnsafeCell::new(Target {
+            pixels: vec![BltPixel::new(0, 0, 0); width * height],
+            depth: vec![f32::INFINITY; width * height],
+        });
+        let output = if supersample > 1 { vec![BltPixel::new(0, 0, 0); screen.0 * screen.1] } else { Vec::new() };
+        Self {
+            width,
+            height,
+            buffers: [blank(), blank()],
+            back: AtomicUsize::new(0),
+            supersample,
+            output: UnsafeCell::new(output),
+            tone: None,
+        }
+    }
+
+    /// Rebuilds the lookup table presented frames go through: each channel is raised to
+    /// `1 / gamma` then scaled by `brightness`. Call before the render cores start.
+    pub fn set_tone(&mut self, gamma: f32, brightness: f32) {
+        if gamma == 1.0 && brightness == 1.0 {
+            self.tone = None;
+            return;
+        }
+        let mut lut = [0; 256];
+        for (i, level) in lut.iter_mut().enumerate() {
+            let linear = Vec3::splat(i as f32 / 255.0).powf(1.0 / gamma).x * brightness;
+            *level = (linear.clamp(0.0, 1.0) * 255.0 + 0.5) as u8;
+        }
+        self.tone = Some(lut);
+    }
+
+    /// Resolution the frame is traced at
+    pub fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    pub fn supersample(&self) -> usize {
+        self.supersample
+    }
+
+    /// Copies the `width` wide `tile` and its `depth` with the top-left corner at `origin`
+    /// into the back buffer
+    pub fn write_tile(&self, origin: (usize, usize), width: usize, tile: &[BltPixel], depth: &[f32]) {
+        let back = self.buffers[self.back.load(Ordering::Acquire)].get();
+        for (row, (line, line_depth)) in tile.chunks(width).zip(depth.chunks(width)).enumerate() {
+            let start = (origin.1 + row) * self.width + origin.0;
+            // Through raw pointers, other cores are writing other tiles of the same buffer
+            unsafe {
+                line.as_ptr().copy_to_nonoverlapping((*back).pixels.as_mut_ptr().add(start), line.len());
+                line_depth.as_ptr().copy_to_nonoverlapping((*back).depth.as_mut_ptr().add(start), line.len());
+            }
+        }
+    }
+
+    /// Hands the whole back buffer, its depth and its width to `f`, for overlays drawn across
+    /// tiles. Only once every core has written its tiles for the frame.
+    pub fn draw_over(&self, f: impl FnOnce(&mut [BltPixel], &[f32], usize)) {
+        let back = unsafe { &mut *self.buffers[self.back.load(Ordering::Acquire)].get() };
+        f(&mut back.pixels, &back.depth, self.width);
+    }
+
+    /// Shows the finished back buffer and starts drawing into the other one. Supersampled
+    /// frames are box-filtered down first, then everything goes through the tone table. `overlay` gets the screen-sized frame and its width
+    /// just before it goes out.
+    pub fn present(&self, scr: &mut Screen, grade: ColorGrade, overlay: impl FnOnce(&mut [BltPixel], usize)) -> Result {
+        let back = self.back.load(Ordering::Acquire);
+        let frame = unsafe { &mut (*self.buffers[back].get()).pixels };
+        let n = self.supersample;
+        let (width, height) = (self.width / n, self.height / n);
+        let buffer = if n == 1 { frame } else {
+            let output = unsafe { &mut *self.output.get() };
+            for (i, out) in output.iter_mut().enumerate() {
+                let (x, y) = (i % width * n, i / width * n);
+                let mut sum = [0u32; 3];
+                for p in (0..n).flat_map(|dy| &frame[(y + dy) * self.width + x..][..n]) {
+                    sum[0] += p.red as u32;
+                    sum[1] += p.green as u32;
+                    sum[2] += p.blue as u32;
+                }
+                let count = (n * n) as u32;
+                *out = BltPixel::new((sum[0] / count) as u8, (sum[1] / count) as u8, (sum[2] / count) as u8);
+            }
+            output
+        };
+        // Before the overlay so text keeps its exact colour
+        if let Some(lut) = &self.tone {
+            for p in buffer.iter_mut() {
+                *p = BltPixel::new(lut[p.red as usize], lut[p.green as usize], lut[p.blue as usize]);
+            }
+        }
+        overlay(buffer, width);
+        // After the overlay, so the HUD matches the rest of the frame
+        grade.apply(buffer, width);
+        t!(scr.gop.blt(BltOp::BufferToVideo {
+            buffer,
+            src: BltRegion::Full,
+            dest: (0, 0),
+            dims: (width, height),
+        }));
+        self.back.store(back ^ 1, Ordering::Release);
+        OK
+    }
+
+    /// The frame the last `present` put on screen, tone and overlay included, and its width.
+    /// Only valid between frames, once the next one has not started writing tiles.
+    pub fn presented(&self) -> (&[BltPixel], usize) {
+        let width = self.width / self.supersample;
+        if self.supersample > 1 {
+            return (unsafe { &*self.output.get() }, width);
+        }
+        let front = self.back.load(Ordering::Acquire) ^ 1;
+        (unsafe { &(*self.buffers[front].get()).pixels }, width)
+    }
+}
+
+/// The camera a frame was traced with, what depth-tested overlays are projected through
+#[derive(Clone, Copy)]
+pub struct OverlayCamera<'a> {
+    pub view_proj: &'a Mat4,
+    pub eye: Vec3,
+    /// Pixels a block spans at distance 1
+    pub focal: f32,
+}
+
+/// World-space marker drawn over the frame as a diamond that shrinks with distance
+#[derive(Clone, Copy, Debug)]
+pub struct Billboard {
+    pub pos: Vec3,
+    pub color: BltPixel,
+    /// Half the diamond's height in blocks
+    pub size: f32,
+}
+
+impl Billboard {
+    /// Draws into the `width` wide frame `fb` where nearer to the camera's eye than `depth`
+    pub fn draw(&self, fb: &mut [BltPixel], depth: &[f32], width: usize, camera: &OverlayCamera) {
+        let height = fb.len() / width;
+        let dist = self.pos.distance(camera.eye);
+        let radius = (self.size * camera.focal / dist) as isize;
+        let Some((cx, cy, _)) = project(camera.view_proj, self.pos, (width, height), radius as f32) else { return };
+
+        for y in (cy - radius).max(0)..=(cy + radius).min(height as isize - 1) {
+            let reach = radius - (y - cy).abs();
+            for x in (cx - reach).max(0)..=(cx + reach).min(width as isize - 1) {
+                let i = y as usize * width + x as usize;
+                if dist < depth[i] {
+                    fb[i] = self.color;
+                }
+            }
+        }
+    }
+}
+
+/// Screen pixel of world point `pos` and its clip w. None behind the near plane, so the divide
+/// stays safe, or when not even a `margin` pixel shape around it would be on screen.
+pub fn project(view_proj: &Mat4, pos: Vec3, (width, height): (usize, usize), margin: f32) -> Option<(isize, isize, f32)> {
+    let clip = *view_proj * pos.extend(1.0);
+    if clip.w <= PROJECT_NEAR { return None }
+    let x = (clip.x / clip.w + 1.0) * 0.5 * width as f32;
+    let y = (1.0 - clip.y / clip.w) * 0.5 * height as f32;
+    let outside = x < -margin || y < -margin || x > width as f32 + margin || y > height as f32 + margin;
+    (!outside).then_some((x as isize, y as isize, clip.w))
+}
+
+/// Points closer to the eye than this are not projected
+const PROJECT_NEAR: f32 = 0.1;
+
+/// Top solid block of every column of the world footprint, all `ray_march_heightmap` sees.
+/// A snapshot, core 0 refreshes the columns `World::take_dirty_columns` reports before each frame.
+pub struct Heightmap {
+    size: i32,
+    /// `(y, block id)` per column, y is -1 where there is nothing solid
+    columns: Vec<(i32, u8)>,
+}
+
+impl Heightmap {
+    /// Heights from the world's column cache, ids from one pass over the block list
+    pub fn new(world: &World) -> Self {
+        let size = world.size.max(0);
+        let mut map = Self { size, columns: vec![(-1, AIR); (size * size) as usize] };
+        map.refresh(world, IVec2::ZERO, IVec2::splat(size - 1));
+        map
+    }
+
+    /// Resamples the columns in the inclusive x/z box `min..=max` after the world was edited there
+    pub fn refresh(&mut self, world: &World, min: IVec2, max: IVec2) {
+        let (min, max) = (min.max(IVec2::ZERO), max.min(IVec2::splat(self.size - 1)));
+        if min.cmpgt(max).any() { return }
+        let index = |x: i32, z: i32| (z * self.size + x) as usize;
+        for z in min.y..=max.y {
+            for x in min.x..=max.x {
+                self.columns[index(x, z)] = (world.column_top(x, z).unwrap_or(-1), AIR);
+            }
+        }
+        for (pos, block) in world.iter_blocks() {
+            if pos.x < min.x || pos.x > max.x || pos.z < min.y || pos.z > max.y { continue }
+            let column = &mut self.columns[index(pos.x, pos.z)];
+            if column.0 == pos.y && block_def(block.id).solid {
+                column.1 = block.id;
+            }
+        }
+    }
+
+    /// Top solid block of column `x, z` as `(y, id)`, `None` for empty columns and outside the footprint
+    pub fn get(&self, x: i32, z: i32) -> Option<(i32, u8)> {
+        let inside = (0..self.size).contains(&x) && (0..self.size).contains(&z);
+        inside.then(|| self.columns[(z * self.size + x) as usize]).filter(|c| c.0 >= 0)
+    }
+}
+
+/// Edge of the minimap in pixels, one per column
+pub const MINIMAP_SIZE: usize = 64;
+/// Columns with nothing solid, and outside the world
+const MINIMAP_EMPTY: BltPixel = BltPixel::new(16, 16, 24);
+const MINIMAP_PLAYER: BltPixel = BltPixel::new(255, 40, 40);
+/// Length of the facing line from the centre, in pixels
+const MINIMAP_ARROW: i32 = 6;
+/// Top blocks darken this much per block below `MINIMAP_SHADE_TOP`, so relief shows
+const MINIMAP_SHADE_PER_BLOCK: f32 = 0.02;
+const MINIMAP_SHADE_TOP: i32 = 32;
+const MINIMAP_SHADE_MIN: f32 = 0.4;
+
+/// Top-down view of a `Heightmap` around a centre column, one pixel per column, +X right and +Z down
+pub struct Minimap {
+    center: Option<IVec2>,
+    pixels: Vec<BltPixel>,
+}
+
+impl Minimap {
+    pub fn new() -> Self {
+        Self { center: None, pixels: vec![MINIMAP_EMPTY; MINIMAP_SIZE * MINIMAP_SIZE] }
+    }
+
+    /// Recentres on column `center`. Pixels still on the map are moved, only the rows and
+    /// columns that scrolled in are sampled.
+    pub fn update(&mut self, map: &Heightmap, center: IVec2) {
+        let n = MINIMAP_SIZE as i32;
+        let shift = self.center.map(|old| center - old);
+        if shift == Some(IVec2::ZERO) { return }
+        let old = core::mem::replace(&mut self.pixels, vec![MINIMAP_EMPTY; MINIMAP_SIZE * MINIMAP_SIZE]);
+        for py in 0..n {
+            for px in 0..n {
+                let pixel = IVec2::new(px, py);
+                let kept = shift.map(|s| pixel + s)
+                    .filter(|p| p.cmpge(IVec2::ZERO).all() && p.cmplt(IVec2::splat(n)).all());
+                self.pixels[(py * n + px) as usize] = match kept {
+                    Some(p) => old[(p.y * n + p.x) as usize],
+                    None => Self::sample(map, center + pixel - IVec2::splat(n / 2)),
+                };
+            }
+        }
+        self.center = Some(center);
+    }
+
+    /// Resamples the pixels of the columns in the inclusive x/z box `min..=max`, after `map`
+    /// was refreshed there
+    pub fn invalidate(&mut self, map: &Heightmap, min: IVec2, max: IVec2) {
+        let Some(center) = self.center else { return };
+        let n = MINIMAP_SIZE as i32;
+        let corner = center - IVec2::splat(n / 2);
+        let (lo, hi) = ((min - corner).max(IVec2::ZERO), (max - corner).min(IVec2::splat(n - 1)));
+        for py in lo.y..=hi.y {
+            for px in lo.x..=hi.x {
+                self.pixels[(py * n + px) as usize] = Self::sample(map, corner + IVec2::new(px, py));
+            }
+        }
+    }
+
+    fn sample(map: &Heightmap, column: IVec2) -> BltPixel {
+        let Some((y, id)) = map.get(column.x, column.y) else { return MINIMAP_EMPTY };
+        let shade = (1.0 - (MINIMAP_SHADE_TOP - y).max(0) as f32 * MINIMAP_SHADE_PER_BLOCK).max(MINIMAP_SHADE_MIN);
+        let c = block_def(id).color * shade * 255.0;
+        BltPixel::new(c.x as u8, c.y as u8, c.z as u8)
+    }
+
+    /// Copies the map into the `width` wide `fb` with its top-left corner at `(x, y)`, with a
+    /// dot on the centre column and a line along `facing` (x/z)
+    pub fn draw(&self, fb: &mut [BltPixel], width: usize, (x, y): (usize, usize), facing: Vec2) {
+        let height = fb.len() / width;
+        let mut put = |px: i32, py: i32, color: BltPixel| {
+            if !(0..MINIMAP_SIZE as i32).contains(&px) || !(0..MINIMAP_SIZE as i32).contains(&py) { return }
+            let (sx, sy) = (x + px as usize, y + py as usize);
+            if sx < width && sy < height {
+                fb[sy * width + sx] = color;
+            }
+        };
+        for (i, &p) in self.pixels.iter().enumerate() {
+            put((i % MINIMAP_SIZE) as i32, (i / MINIMAP_SIZE) as i32, p);
+        }
+        let mid = MINIMAP_SIZE as i32 / 2;
+        for step in 0..=MINIMAP_ARROW {
+            let p = (facing * step as f32).round().as_ivec2();
+            put(mid + p.x, mid + p.y, BltPixel::new(255, 255, 255));
+        }
+        for (dx, dy) in [(0, 0), (-1, 0), (1, 0), (0, -1), (0, 1)] {
+            put(mid + dx, mid + dy, MINIMAP_PLAYER);
+        }
+    }
+}
+
+/// Comanche-style march over `map`: walks the columns under the ray from `origin` along the
+/// x/z part of `dir`, front to back and up to `max_dist` blocks across the ground. Calls
+/// `column(top block, id, ground distance to where the ray enters the column)` for every
+/// non-empty one until it returns false. Caves and overhangs don't exist in this view.
+pub fn ray_march_heightmap(
+    map: &Heightmap, origin: Vec3, dir: Vec3, max_dist: f32, mut column: impl FnMut(IVec3, u8, f32) -> bool,
+) {
+    let Some(flat) = Vec2::new(dir.x, dir.z).try_normalize() else { return };
+    // Blocks are centred on integer coordinates
+    let start = Vec2::new(origin.x, origin.z) + Vec2::splat(0.5);
+    let mut cell = start.floor().as_ivec2();
+    let step = IVec2::new(flat.x.signum() as i32, flat.y.signum() as i32);
+    let delta = Vec2::new(1.0 / flat.x.abs(), 1.0 / flat.y.abs());
+    // Ground distance to the next cell boundary on each axis, never along one the ray is parallel to
+    let first = |s: f32, c: i32, d: f32| match d {
+        d if d > 0.0 => (c as f32 + 1.0 - s) / d,
+        d if d < 0.0 => (s - c as f32) / -d,
+        _ => f32::INFINITY,
+    };
+    let mut next = Vec2::new(first(start.x, cell.x, flat.x), first(start.y, cell.y, flat.y));
+    let mut dist = 0.0;
+    while dist <= max_dist {
+        if let Some((y, id)) = map.get(cell.x, cell.y) && !column(IVec3::new(cell.x, y, cell.y), id, dist) {
+            return;
+        }
+        if next.x < next.y {
+            dist = next.x;
+            next.x += delta.x;
+            cell.x += step.x;
+        } else {
+            dist = next.y;
+            next.y += delta.y;
+            cell.y += step.y;
+        }
+    }
+}
+
+/// 4x4 Bayer matrix, thresholds 0..16
+const BAYER4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Ordered dither threshold at screen position `(x, y)`, in 0..1
+fn bayer((x, y): (usize, usize)) -> f32 {
+    (BAYER4[y % 4][x % 4] as f32 + 0.5) / 16.0
+}
+
+/// Post-grade over the whole presented frame, HUD included
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorGrade {
+    None,
+    /// 256 colours as 3-3-2 bits of red, green and blue, dithered between levels
+    Vga,
+    Sepia,
+    /// Steeper around mid grey, clipping sooner at both ends
+    HighContrast,
+}
+
+impl ColorGrade {
+    /// Grades the `width` wide frame in place
+    fn apply(self, buffer: &mut [BltPixel], width: usize) {
+        match self {
+            ColorGrade::None => {}
+            ColorGrade::Vga => {
+                // Levels per channel minus one
+                let steps = Vec3::new(7.0, 7.0, 3.0);
+                for (i, p) in buffer.iter_mut().enumerate() {
+                    let c = Vec3::new(p.red as f32, p.green as f32, p.blue as f32) / 255.0 * steps;
+                    let q = (c + Vec3::splat(bayer((i % width, i / width)))).floor().min(steps) / steps * 255.0;
+                    *p = BltPixel::new(q.x as u8, q.y as u8, q.z as u8);
+                }
+            }
+            ColorGrade::Sepia => {
+                for p in buffer.iter_mut() {
+                    let (r, g, b) = (p.red as f32, p.green as f32, p.blue as f32);
+                    let tone = |kr: f32, kg: f32, kb: f32| (r * kr + g * kg + b * kb).min(255.0) as u8;
+                    *p = BltPixel::new(tone(0.393, 0.769, 0.189), tone(0.349, 0.686, 0.168), tone(0.272, 0.534, 0.131));
+                }
+            }
+            ColorGrade::HighContrast => {
+                let mut lut = [0u8; 256];
+                for (i, level) in lut.iter_mut().enumerate() {
+                    *level = ((i as f32 - 128.0) * HIGH_CONTRAST + 128.0).clamp(0.0, 255.0) as u8;
+                }
+                for p in buffer.iter_mut() {
+                    *p = BltPixel::new(lut[p.red as usize], lut[p.green as usize], lut[p.blue as usize]);
+                }
+            }
+        }
+    }
+}
+
+/// Slope of `ColorGrade::HighContrast` around mid grey
+const HIGH_CONTRAST: f32 = 1.6;
+
+/// 8-bit pixel for a 0..1 `color` at screen position `(x, y)`. With `dither`, each channel gets
+/// a fixed per-position offset of under one level before truncating, so smooth gradients
+/// break up into a stable pattern instead of bands.
+pub fn quantize(color: Vec3, (x, y): (usize, usize), dither: bool) -> BltPixel {
+    let offset = if dither { bayer((x, y)) } else { 0.0 };
+    let c = color * 255.0 + Vec3::splat(offset);
+    BltPixel::new(c.x as u8, c.y as u8, c.z as u8)
+}
+
+/// Pixels from the centre to the tip of each crosshair arm
+const CROSSHAIR_ARM: isize = 5;
+
+/// Inverts a plus at the centre of a `screen`-sized frame, clipped to the `width` wide `tile`
+/// whose top-left corner sits at `origin`. Inverting keeps it visible against both sky and dark blocks.
+pub fn draw_crosshair(tile: &mut [BltPixel], origin: (usize, usize), width: usize, screen: (usize, usize)) {
+    let (cx, cy) = ((screen.0 / 2) as isize, (screen.1 / 2) as isize);
+    let height = (tile.len() / width) as isize;
+
+    let horizontal = (-CROSSHAIR_ARM..=CROSSHAIR_ARM).map(|d| (cx + d, cy));
+    let vertical = (-CROSSHAIR_ARM..=CROSSHAIR_ARM).filter(|&d| d != 0).map(|d| (cx, cy + d));
+    for (x, y) in horizontal.chain(vertical) {
+        let (lx, ly) = (x - origin.0 as isize, y - origin.1 as isize);
+        if !(0..width as isize).contains(&lx) || !(0..height).contains(&ly) { continue }
+        let p = &mut tile[(ly * width as isize + lx) as usize];
+        *p = BltPixel::new(255 - p.red, 255 - p.green, 255 - p.blue);
+    }
+}
+
+/// How `draw_text` sets glyphs off from whatever is behind them
+#[derive(Clone, Copy, Debug)]
+pub enum TextStyle {
+    Plain,
+    /// A 1 pixel ring around every glyph in this colour
+    Outline(BltPixel),
+    /// A copy of the text 1 pixel down and right in this colour
+    Shadow(BltPixel),
+}
+
+/// Draws `text` with its top-left corner at screen position `(x, y)` into the `width` wide
+/// `tile` at `origin`, foreground pixels and `style` only. Clipped to the tile, newlines and
+/// the screen edge don't wrap.
+pub fn draw_text(
+    tile: &mut [BltPixel], origin: (usize, usize), width: usize, (x, y): (usize, usize), text: &str, fg: BltPixel,
+    style: TextStyle,
+) {
+    let height = tile.len() / width;
+    // A pixel of slack on each side for the outline and shadow
+    if y + 17 < origin.1 || y > origin.1 + height { return }
+    let (x, y) = (x as isize, y as isize);
+
+    for (i, c) in text.chars().enumerate() {
+        let gx = x + i as isize * 8;
+        if gx > (origin.0 + width) as isize { break }
+        if gx + 9 < origin.0 as isize { continue }
+
+        let glyph = FONT_8X16[(c as usize) & 0x7F].map(u16::from);
+        match style {
+            TextStyle::Plain => {}
+            TextStyle::Outline(color) => {
+                // The glyph grown by a pixel each way, 10x18 with the glyph at (1, 1)
+                let mut ring = [0u16; 18];
+                for (r, bits) in glyph.into_iter().enumerate() {
+                    for out in &mut ring[r..r + 3] {
+                        *out |= bits << 2 | bits << 1 | bits;
+                    }
+                }
+                draw_mask(tile, origin, width, (gx - 1, y - 1), &ring, 10, color);
+            }
+            TextStyle::Shadow(color) => draw_mask(tile, origin, width, (gx + 1, y + 1), &glyph, 8, color),
+        }
+        draw_mask(tile, origin, width, (gx, y), &glyph, 8, fg);
+    }
+}
+
+/// Sets the pixels of a 1-bit `mask`, rows `bits` wide with the leftmost pixel in the top bit,
+/// with its top-left corner at screen position `(x, y)`. Clipped to the `width` wide `tile` at `origin`.
+fn draw_mask(
+    tile: &mut [BltPixel], origin: (usize, usize), width: usize, (x, y): (isize, isize), mask: &[u16], bits: usize,
+    color: BltPixel,
+) {
+    let height = tile.len() / width;
+    for (r, &row) in mask.iter().enumerate() {
+        let ty = y + r as isize - origin.1 as isize;
+        if row == 0 || ty < 0 || ty >= height as isize { continue }
+        for col in 0..bits {
+            let tx = x + col as isize - origin.0 as isize;
+            if tx < 0 || tx >= width as isize || (row >> (bits - 1 - col)) & 1 == 0 { continue }
+            tile[ty as usize * width + tx as usize] = color;
+        }
+    }
+}
+
+/// Texels along each edge of a block face
+const TEXTURE_RES: f32 = 16.0;
+const BRICK_HEIGHT: u32 = 4;
+const BRICK_WIDTH: u32 = 8;
+const MORTAR: Vec3 = rgb(170, 165, 155);
+/// Face of a block as passed to `sample_texture`
+pub const FACE_TOP: u8 = 2;
+
+/// Face index of an outward `normal`: 0/1 for +X/-X, 2/3 for +Y/-Y, 4/5 for +Z/-Z
+pub fn face_index(normal: IVec3) -> u8 {
+    let axis = if normal.x != 0 { 0 } else if normal.y != 0 { 1 } else { 2 };
+    axis * 2 + (normal.cmplt(IVec3::ZERO).any() as u8)
+}
+
+/// Colour of `block` at `(u, v)` (0..1) on `face`, patterns hashed from the texel
+/// coordinates so no texture memory is needed. Blocks without a pattern are flat.
+pub fn sample_texture(block: u8, face: u8, u: f32, v: f32) -> Vec3 {
+    let base = block_def(block).color;
+    let tx = (u * TEXTURE_RES).clamp(0.0, TEXTURE_RES - 1.0) as u32;
+    let ty = (v * TEXTURE_RES).clamp(0.0, TEXTURE_RES - 1.0) as u32;
+    let noise = texel_hash(block, face, tx, ty);
+
+    match block {
+        STONE => base * (0.85 + 0.25 * noise),
+        DIRT | SAND => base * (0.92 + 0.16 * noise),
+        BRICK => {
+            // Every other course is shifted by half a brick
+            let course = ty / BRICK_HEIGHT;
+            let shift = if course % 2 == 1 { BRICK_WIDTH / 2 } else { 0 };
+            if ty.is_multiple_of(BRICK_HEIGHT) || (tx + shift).is_multiple_of(BRICK_WIDTH) {
+                MORTAR
+            } else {
+                base * (0.9 + 0.15 * noise)
+            }
+        }
+        // Grain runs along v, one hash per texel column
+        WOOD => base * (0.8 + 0.25 * texel_hash(block, face, tx, 0)),
+        GRASS if face == FACE_TOP => {
+            let check = if (tx / 2 + ty / 2).is_multiple_of(2) { 1.05 } else { 0.92 };
+            base * check * (0.95 + 0.1 * noise)
+        }
+        GRASS => base * (0.9 + 0.15 * noise),
+        _ => base,
+    }
+}
+
+/// 0..1 from a cheap integer hash of one texel
+fn texel_hash(block: u8, face: u8, tx: u32, ty: u32) -> f32 {
+    let mut h = (block as u32) << 24 | (face as u32) << 16 | ty << 8 | tx;
+    h = (h ^ (h >> 16)).wrapping_mul(0x7feb_352d);
+    h = (h ^ (h >> 15)).wrapping_mul(0x846c_a68b);
+    h ^= h >> 16;
+    (h >> 8) as f32 / (1 << 24) as f32
+}
@@ -0,0 +1,32 @@
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use uefi::boot;
+
+/// How long `calibrate` times the TSC against the firmware's stall
+const CALIBRATION: Duration = Duration::from_millis(20);
+
+/// TSC ticks per millisecond, 0 until `calibrate`
+static TICKS_PER_MS: AtomicU64 = AtomicU64::new(0);
+/// TSC reading `now_ms` counts from
+static EPOCH: AtomicU64 = AtomicU64::new(0);
+
+/// Measures the TSC rate against `boot::stall` and starts the clock, returns ticks per millisecond.
+/// Core 0 only, once at startup while boot services are up.
+pub fn calibrate() -> u64 {
+    let start = unsafe { _rdtsc() };
+    boot::stall(CALIBRATION);
+    let ticks = unsafe { _rdtsc() } - start;
+    let per_ms = (ticks / CALIBRATION.as_millis() as u64).max(1);
+    EPOCH.store(start, Ordering::Relaxed);
+    TICKS_PER_MS.store(per_ms, Ordering::Release);
+    per_ms
+}
+
+/// Milliseconds since `calibrate`, 0 before it. The TSC runs at a fixed rate and in step on
+/// every core of anything recent, so any core may read it.
+pub fn now_ms() -> u64 {
+    let per_ms = TICKS_PER_MS.load(Ordering::Acquire);
+    if per_ms == 0 { return 0 }
+    unsafe { _rdtsc() }.saturating_sub(EPOCH.load(Ordering::Relaxed)) / per_ms
+}
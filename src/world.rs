@@ -1,5 +1,66 @@
+use alloc::format;
+use alloc::string::ToString;
 use alloc::vec::Vec;
-use glam::{Vec3, vec3};
+use core::arch::x86_64::_rdtsc;
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use glam::{IVec2, IVec3, Vec3, vec3};
+use uefi::boot::{get_handle_for_protocol, get_image_file_system, image_handle, open_protocol_exclusive};
+use uefi::fs::{FileSystem, Path};
+use uefi::proto::rng::Rng;
+use uefi::{cstr16, runtime, CStr16, CString16};
+use crate::error::{ErrorType, Result, OK};
+use crate::render::Screen;
+use crate::{t, throw};
+
+pub mod blocks;
+pub mod light;
+pub mod structures;
+pub mod builder;
+#[cfg(test)]
+mod tests;
+pub use blocks::*;
+pub use light::{LightMap, MAX_LIGHT};
+pub use structures::StructureId;
+pub use builder::WorldBuilder;
+
+/// Edge length of the world footprint when none is given.
+/// Kept small while the renderer tests every block for every pixel.
+pub const DEFAULT_WORLD_SIZE: i32 = 64;
+/// Largest footprint a save may declare, the per-column maps are allocated up front
+const MAX_WORLD_SIZE: i32 = 1024;
+const TERRAIN_MIN_HEIGHT: i32 = 1;
+const TERRAIN_MAX_HEIGHT: i32 = 20;
+/// Exclusive upper bound for y, nothing is stored at or above it
+pub const WORLD_HEIGHT: i32 = 128;
+/// How far rays look when the world wraps, where they would otherwise never leave it
+const WRAP_RAY_DIST: f32 = 100.0;
+/// Number of edits kept by `World::recent_edits`
+pub const EDIT_LOG_LEN: usize = 32;
+/// Terrain below this height is flooded with water
+pub const SEA_LEVEL: i32 = 6;
+
+/// Save file layout: a header, then (x, y, z: i32, id: u8) per block, all little-endian.
+/// Version 1 files have no world size field, later versions each append header fields.
+const SAVE_MAGIC: [u8; 4] = *b"UCWD";
+const SAVE_VERSION: u32 = 4;
+/// magic, version, size, block count, save counter, CRC-32 of everything after it,
+/// then spawn set flag and spawn x, y, z (f32)
+const SAVE_HEADER_LEN: usize = 40;
+const SAVE_HEADER_LEN_V3: usize = 24;
+const SAVE_CRC_AT: usize = 20;
+const SAVE_HEADER_LEN_V2: usize = 16;
+const SAVE_HEADER_LEN_V1: usize = 12;
+const SAVE_BLOCK_LEN: usize = 13;
+
+/// Structure blobs share the RLE size header, followed by one raw id per voxel
+const STRUCT_HEADER_LEN: usize = 6;
+/// Structure voxel that leaves the world untouched, for hollow masks
+pub const STRUCT_KEEP: u8 = 255;
+
+const RLE_HEADER_LEN: usize = 6;
+const RLE_RUN_LEN: usize = 3;
 
 #[derive(Clone, Copy, Debug)]
 pub struct Block {
@@ -7,32 +68,1538 @@ pub struct Block {
 }
 
 impl Block {
+    pub const AIR: Self = Self { id: AIR };
+
     pub fn new(id: u8) -> Self {
         Self { id }
     }
 }
 
+/// Single-voxel reads, for code that can run against a world other than the block list,
+/// such as the dense arrays the host tests compare it with
+pub trait BlockSource {
+    /// Id of the block at `pos`, `AIR` where there is none
+    fn get_block(&self, pos: IVec3) -> u8;
+
+    /// Stops primary rays. Transparent blocks only tint what is behind them.
+    fn is_opaque(&self, pos: IVec3) -> bool {
+        !block_def(self.get_block(pos)).transparent
+    }
+}
+
+impl BlockSource for World {
+    fn get_block(&self, pos: IVec3) -> u8 {
+        World::get_block(self, pos).id
+    }
+}
+
+/// Result of `World::raycast`
+#[derive(Clone, Copy, Debug)]
+pub struct RayHit {
+    pub pos: IVec3,
+    pub block: Block,
+    /// Outward normal of the face that was hit, zero when the ray starts inside the block
+    pub normal: IVec3,
+    pub dist: f32,
+}
+
+/// One transparent block on a ray, from `World::crossings`
+#[derive(Clone, Copy, Debug)]
+pub struct Crossing {
+    /// Stored position, not wrapped into the copy the ray went through
+    pub pos: IVec3,
+    pub block: Block,
+    /// Outward normal of the face the ray entered through, zero when it starts inside
+    pub normal: IVec3,
+    /// Where the ray enters and leaves the block, `far` cut at the ray's end
+    pub near: f32,
+    pub far: f32,
+}
+
+impl Crossing {
+    const NONE: Self = Self { pos: IVec3::ZERO, block: Block::AIR, normal: IVec3::ZERO, near: 0.0, far: 0.0 };
+}
+
+/// The nearest `N` crossings of a ray in order, kept on the stack so shading doesn't allocate per pixel
+#[derive(Clone, Copy)]
+pub struct Crossings<const N: usize> {
+    items: [Crossing; N],
+    len: usize,
+}
+
+impl<const N: usize> Crossings<N> {
+    const fn new() -> Self {
+        Self { items: [Crossing::NONE; N], len: 0 }
+    }
+
+    /// Slots `c` in by `near`, dropping the farthest once full
+    fn insert(&mut self, c: Crossing) {
+        if self.len == N && self.items[N - 1].near <= c.near { return }
+        let at = self.items[..self.len].partition_point(|o| o.near <= c.near);
+        self.len = (self.len + 1).min(N);
+        self.items.copy_within(at..self.len - 1, at + 1);
+        self.items[at] = c;
+    }
+}
+
+impl<const N: usize> core::ops::Deref for Crossings<N> {
+    type Target = [Crossing];
+
+    fn deref(&self) -> &[Crossing] {
+        &self.items[..self.len]
+    }
+}
+
+/// One `set_block` call as recorded in the edit log
+#[derive(Clone, Copy, Debug)]
+pub struct Edit {
+    pub pos: IVec3,
+    pub old: Block,
+    pub new: Block,
+}
+
+/// Fixed-size ring of the most recent edits, oldest entries are overwritten
+pub struct EditLog {
+    ring: [Edit; EDIT_LOG_LEN],
+    /// Next slot to write
+    head: usize,
+    len: usize,
+}
+
+impl EditLog {
+    pub const fn new() -> Self {
+        const EMPTY: Edit = Edit { pos: IVec3::ZERO, old: Block::AIR, new: Block::AIR };
+        Self { ring: [EMPTY; EDIT_LOG_LEN], head: 0, len: 0 }
+    }
+
+    pub fn push(&mut self, edit: Edit) {
+        self.ring[self.head] = edit;
+        self.head = (self.head + 1) % EDIT_LOG_LEN;
+        self.len = (self.len + 1).min(EDIT_LOG_LEN);
+    }
+
+    /// Newest first
+    pub fn iter(&self) -> impl Iterator<Item = &Edit> + '_ {
+        (1..=self.len).map(|i| &self.ring[(self.head + EDIT_LOG_LEN - i) % EDIT_LOG_LEN])
+    }
+}
+
+/// Optional state byte per voxel (log axis, stair facing, ...) for block types with `has_meta`.
+/// Open addressing with linear probing over a power-of-two table keyed by Morton code + 1,
+/// so the zero key marks an empty slot. Nothing is allocated until the first non-zero write.
+pub struct MetaPool {
+    slots: Vec<(u64, u8)>,
+    /// Live entries plus tombstones, what the load factor is checked against
+    used: usize,
+}
+
+const META_EMPTY: u64 = 0;
+const META_TOMBSTONE: u64 = u64::MAX;
+
+impl MetaPool {
+    pub const fn new() -> Self {
+        Self { slots: Vec::new(), used: 0 }
+    }
+
+    /// 0 when nothing is stored
+    pub fn get(&self, pos: IVec3) -> u8 {
+        self.find(morton(pos) + 1).map_or(0, |i| self.slots[i].1)
+    }
+
+    /// Storing 0 removes the entry
+    pub fn set(&mut self, pos: IVec3, meta: u8) {
+        let key = morton(pos) + 1;
+        if let Some(i) = self.find(key) {
+            if meta == 0 {
+                self.slots[i].0 = META_TOMBSTONE;
+            } else {
+                self.slots[i].1 = meta;
+            }
+            return;
+        }
+        if meta == 0 { return }
+
+        if (self.used + 1) * 4 > self.slots.len() * 3 {
+            self.rehash();
+        }
+        let mask = self.slots.len() - 1;
+        let mut i = mix64(key) as usize & mask;
+        while self.slots[i].0 != META_EMPTY && self.slots[i].0 != META_TOMBSTONE {
+            i = (i + 1) & mask;
+        }
+        if self.slots[i].0 == META_EMPTY {
+            self.used += 1;
+        }
+        self.slots[i] = (key, meta);
+    }
+
+    fn find(&self, key: u64) -> Option<usize> {
+        if self.slots.is_empty() { return None }
+        let mask = self.slots.len() - 1;
+        let mut i = mix64(key) as usize & mask;
+        loop {
+            match self.slots[i].0 {
+                META_EMPTY => return None,
+                k if k == key => return Some(i),
+                _ => i = (i + 1) & mask,
+            }
+        }
+    }
+
+    /// Drops tombstones and doubles the table when the live entries need it
+    fn rehash(&mut self) {
+        let live: Vec<(u64, u8)> = self.slots.iter()
+            .copied()
+            .filter(|(k, _)| *k != META_EMPTY && *k != META_TOMBSTONE)
+            .collect();
+        let cap = ((live.len() + 1) * 2).next_power_of_two().max(16);
+        self.slots = alloc::vec![(META_EMPTY, 0); cap];
+        self.used = live.len();
+        for (key, meta) in live {
+            let mut i = mix64(key) as usize & (cap - 1);
+            while self.slots[i].0 != META_EMPTY {
+                i = (i + 1) & (cap - 1);
+            }
+            self.slots[i] = (key, meta);
+        }
+    }
+}
+
+/// First problem `World::validate` found, with the offending entry of the block list
+#[derive(Debug)]
+pub struct WorldCorruption {
+    pub index: usize,
+    pub pos: Vec3,
+    pub kind: CorruptionKind,
+}
+
+#[derive(Debug)]
+pub enum CorruptionKind {
+    /// Air is never stored
+    AirStored,
+    /// Position is not on the integer voxel grid
+    Misaligned,
+    OutOfBounds,
+    /// Same voxel already stored at the given index
+    Duplicate(usize),
+}
+
+/// Which generator builds a fresh world
+#[derive(Clone, Copy, Debug)]
+pub enum WorldKind {
+    /// Level ground for physics testing
+    Flat { height: i32 },
+    /// Value-noise hills for normal play
+    Terrain { seed: u64 },
+    /// Sparse lattice of single blocks through the whole volume, a worst case for the renderer
+    DebugGrid { spacing: i32 },
+}
+
+/// Memory and extent figures for `World::stats`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WorldStats {
+    /// Stored (non-air) voxels
+    pub entries: usize,
+    pub bytes_used: usize,
+    /// Heap reserved by the block list, including growth slack
+    pub bytes_reserved: usize,
+    /// Highest occupied y, `None` for an empty world
+    pub max_y: Option<i32>,
+}
+
+/// What the world reads as outside of the stored blocks
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WorldBounds {
+    /// Everything below y=0 is bedrock, so nothing falls or looks out of the world
+    #[default]
+    Bedrock,
+    /// Everything not stored is air, for fly/creative testing
+    Open,
+}
+
+/// A write needed more blocks than `World::max_blocks` allows
+#[derive(Debug)]
+pub struct WorldFull {
+    pub blocks: usize,
+    pub max_blocks: usize,
+}
+
+/// Whether the footprint repeats horizontally
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WrapMode {
+    #[default]
+    Off,
+    /// X and Z are taken modulo `size`, so leaving one edge enters the opposite one. Y never wraps.
+    XZ,
+}
+
 pub struct World {
     pub blocks: Vec<(Vec3, Block)>,
+    pub bounds: WorldBounds,
+    pub wrap: WrapMode,
+    /// Edge length of the square x/z footprint generation works in
+    pub size: i32,
+    /// Terrain generation floods everything below this with water
+    pub sea_level: i32,
+    edits: EditLog,
+    light: LightMap,
+    /// Highest solid y per column, -1 if none. Every write path keeps it current.
+    heights: Vec<i32>,
+    /// Highest non-air y per column, the same as `heights` except over water
+    surfaces: Vec<i32>,
+    meta: MetaPool,
+    /// Bumped by every `save_to_file`, restored by loading
+    pub save_count: u32,
+    /// Where players appear, `None` uses the `spawn_point` fallback
+    pub spawn: Option<Vec3>,
+    /// Writes that would grow the block list past this fail with `WorldFull`
+    pub max_blocks: usize,
+    /// Stored blocks per id, kept current by every write path
+    counts: [u32; 256],
+    /// Lowest and highest y of any stored block, `None` when there are none. Kept current by
+    /// every write path, rays that start outside it heading away can't hit anything.
+    y_span: Option<(i32, i32)>,
+    /// Inclusive x/z box of the columns whose top may have changed since the last
+    /// `take_dirty_columns`, what the heightmap snapshot and minimap have to resample
+    dirty_columns: Option<(IVec2, IVec2)>,
 }
 
 impl World {
-    pub fn new() -> Self {
-        Self { blocks: Vec::new() }
+    pub fn with_size(size: i32) -> Self {
+        Self {
+            blocks: Vec::new(),
+            bounds: WorldBounds::default(),
+            wrap: WrapMode::default(),
+            size,
+            sea_level: SEA_LEVEL,
+            edits: EditLog::new(),
+            light: LightMap::empty(),
+            heights: alloc::vec![-1; (size.max(0) * size.max(0)) as usize],
+            surfaces: alloc::vec![-1; (size.max(0) * size.max(0)) as usize],
+            meta: MetaPool::new(),
+            save_count: 0,
+            spawn: None,
+            max_blocks: default_max_blocks(),
+            counts: [0; 256],
+            y_span: None,
+            dirty_columns: None,
+        }
+    }
+
+    pub fn get_block(&self, pos: IVec3) -> Block {
+        if self.bounds == WorldBounds::Bedrock && pos.y < 0 {
+            return Block::new(BEDROCK);
+        }
+        let pos = self.wrap_pos(pos).as_vec3();
+        self.blocks.iter()
+            .find(|(p, _)| *p == pos)
+            .map_or(Block::AIR, |(_, b)| *b)
+    }
+
+    /// Blocks movement, what collision should test. Water is not solid.
+    pub fn is_solid(&self, pos: IVec3) -> bool {
+        block_def(self.get_block(pos).id).solid
+    }
+
+    /// Every stored block with its voxel coordinates
+    pub fn iter_blocks(&self) -> impl Iterator<Item = (IVec3, Block)> + '_ {
+        self.blocks.iter().map(|(pos, block)| (pos.as_ivec3(), *block))
+    }
+
+    /// Number of stored voxels that are solid
+    pub fn count_blocks(&self) -> usize {
+        self.iter_blocks().filter(|(_, block)| block_def(block.id).solid).count()
+    }
+
+    /// How many voxels of each id are stored. Air is never stored, so `[AIR]` stays 0.
+    pub fn census(&self) -> [u32; 256] {
+        self.counts
+    }
+
+    /// Recomputes `census` and `y_span` from the block list after bulk changes
+    fn recount(&mut self) {
+        self.counts = [0; 256];
+        for (_, block) in &self.blocks {
+            self.counts[block.id as usize] += 1;
+        }
+        self.y_span = self.blocks.iter().map(|(pos, _)| pos.y as i32).fold(None, |span, y| {
+            Some(span.map_or((y, y), |(lo, hi): (i32, i32)| (lo.min(y), hi.max(y))))
+        });
+    }
+
+    fn widen_y_span(&mut self, y: i32) {
+        self.y_span = Some(self.y_span.map_or((y, y), |(lo, hi)| (lo.min(y), hi.max(y))));
+    }
+
+    /// Single pass over the block list
+    pub fn stats(&self) -> WorldStats {
+        let entry = size_of::<(Vec3, Block)>();
+        let max_y = self.blocks.iter().map(|(pos, _)| pos.y as i32).max();
+        WorldStats {
+            entries: self.blocks.len(),
+            bytes_used: self.blocks.len() * entry,
+            bytes_reserved: self.blocks.capacity() * entry,
+            max_y,
+        }
+    }
+
+    /// Re-sorts the block list into Morton order, so neighbouring voxels sit next to each other
+    /// again after edits appended and swap-removed entries, and drops the growth slack.
+    /// Returns the bytes freed. Only call it with the world write-locked.
+    pub fn compact(&mut self) -> usize {
+        let before = self.stats().bytes_reserved;
+        self.blocks.sort_unstable_by_key(|(pos, _)| morton(pos.as_ivec3()));
+        self.blocks.shrink_to_fit();
+        before - self.stats().bytes_reserved
+    }
+
+    /// Whether any voxel in the inclusive box `min..=max` is solid, in one pass over the world
+    pub fn query_aabb_solid(&self, min: IVec3, max: IVec3) -> bool {
+        if self.bounds == WorldBounds::Bedrock && min.y < 0 && max.y >= min.y {
+            return true;
+        }
+        self.iter_blocks().any(|(pos, block)| {
+            self.image_in(pos, min, max).is_some() && block_def(block.id).solid
+        })
+    }
+
+    /// Solid voxels of the inclusive box `min..=max` as bits, index `x + sx * (z + sz * y)`
+    /// relative to `min`. The box may hold at most 64 voxels.
+    pub fn query_aabb_solid_mask(&self, min: IVec3, max: IVec3) -> u64 {
+        let size = max - min + IVec3::ONE;
+        if size.cmple(IVec3::ZERO).any() {
+            return 0;
+        }
+        debug_assert!(size.x * size.y * size.z <= 64, "query box {:?} exceeds 64 voxels", size);
+        let bit = |p: IVec3| {
+            let local = p - min;
+            1u64 << (local.x + size.x * (local.z + size.z * local.y))
+        };
+
+        let mut mask = 0;
+        for (pos, block) in self.iter_blocks() {
+            if !block_def(block.id).solid { continue }
+            if let Some(p) = self.image_in(pos, min, max) {
+                mask |= bit(p);
+            }
+        }
+        if self.bounds == WorldBounds::Bedrock {
+            for y in min.y..=max.y.min(-1) {
+                for z in min.z..=max.z {
+                    for x in min.x..=max.x {
+                        mask |= bit(IVec3::new(x, y, z));
+                    }
+                }
+            }
+        }
+        mask
+    }
+
+    /// Where stored voxel `pos` shows up inside the inclusive box `min..=max`, if it does.
+    /// That is `pos` itself, or with wrapping on, its copy shifted by a multiple of `size`.
+    fn image_in(&self, pos: IVec3, min: IVec3, max: IVec3) -> Option<IVec3> {
+        let pos = match self.wrap {
+            WrapMode::Off => pos,
+            WrapMode::XZ => IVec3::new(
+                min.x + (pos.x - min.x).rem_euclid(self.size),
+                pos.y,
+                min.z + (pos.z - min.z).rem_euclid(self.size),
+            ),
+        };
+        (pos.cmpge(min).all() && pos.cmple(max).all()).then_some(pos)
+    }
+
+    /// `pos` moved into the footprint on wrapping axes, unchanged otherwise
+    pub fn wrap_pos(&self, pos: IVec3) -> IVec3 {
+        match self.wrap {
+            WrapMode::Off => pos,
+            WrapMode::XZ => IVec3::new(pos.x.rem_euclid(self.size), pos.y, pos.z.rem_euclid(self.size)),
+        }
+    }
+
+    /// Offsets of the footprint copies a ray of length `max_dist` passes through: just zero
+    /// without wrapping, otherwise every multiple of `size` it crosses within `WRAP_RAY_DIST`
+    fn ray_copies(&self, origin: Vec3, dir: Vec3, max_dist: f32) -> impl Iterator<Item = Vec3> {
+        let (lo, hi) = match self.wrap {
+            WrapMode::Off => (IVec3::ZERO, IVec3::ZERO),
+            WrapMode::XZ => {
+                let end = origin + dir * max_dist.min(WRAP_RAY_DIST);
+                let tile = |v: Vec3| ((v + 0.5) / self.size as f32).floor().as_ivec3().with_y(0);
+                (tile(origin.min(end)), tile(origin.max(end)))
+            }
+        };
+        let size = self.size as f32;
+        (lo.z..=hi.z).flat_map(move |z| (lo.x..=hi.x).map(move |x| vec3(x as f32 * size, 0.0, z as f32 * size)))
+    }
+
+    /// Solid face neighbours of `pos`, bits in order +X, -X, +Y, -Y, +Z, -Z
+    pub fn neighbor_mask6(&self, pos: IVec3) -> u8 {
+        let cube = self.solid_cube(pos);
+        NEIGHBORS6.iter().enumerate()
+            .filter(|(_, d)| cube & cube_bit(**d) != 0)
+            .fold(0, |mask, (i, _)| mask | 1 << i)
+    }
+
+    /// All 26 solid neighbours of `pos`. Bit i is the i-th offset of the 3x3x3 cube in
+    /// x-fastest, then z, then y order, skipping the centre.
+    pub fn neighbor_mask26(&self, pos: IVec3) -> u32 {
+        let cube = self.solid_cube(pos) as u32;
+        // Drop the centre bit (13) and close the gap
+        (cube & 0x1fff) | (cube >> 14) << 13
+    }
+
+    /// 3x3x3 solid mask around `pos` from a single pass over the world
+    fn solid_cube(&self, pos: IVec3) -> u64 {
+        self.query_aabb_solid_mask(pos - IVec3::ONE, pos + IVec3::ONE)
+    }
+
+    /// Checks the block list invariants every query relies on: no air entries, integer
+    /// positions inside the world, and at most one entry per voxel
+    pub fn validate(&self) -> core::result::Result<(), WorldCorruption> {
+        let fail = |index: usize, kind| Err(WorldCorruption { index, pos: self.blocks[index].0, kind });
+
+        for (index, (pos, block)) in self.blocks.iter().enumerate() {
+            if block.id == AIR {
+                return fail(index, CorruptionKind::AirStored);
+            }
+            if pos.as_ivec3().as_vec3() != *pos {
+                return fail(index, CorruptionKind::Misaligned);
+            }
+            if !self.in_bounds(pos.as_ivec3()) {
+                return fail(index, CorruptionKind::OutOfBounds);
+            }
+        }
+
+        let mut order: Vec<(u64, usize)> = self.blocks.iter().enumerate()
+            .map(|(i, (pos, _))| (morton(pos.as_ivec3()), i))
+            .collect();
+        order.sort_unstable();
+        for pair in order.windows(2) {
+            if pair[0].0 == pair[1].0 {
+                return fail(pair[1].1, CorruptionKind::Duplicate(pair[0].1));
+            }
+        }
+        Ok(())
+    }
+
+    /// Inside the editable volume: the x/z footprint, from y=0 up to the ceiling
+    pub fn in_bounds(&self, pos: IVec3) -> bool {
+        (0..self.size).contains(&pos.x) && (0..self.size).contains(&pos.z) && (0..WORLD_HEIGHT).contains(&pos.y)
+    }
+
+    /// Writes `block` at `pos`, replacing whatever was there. Air removes the block.
+    /// Returns the previous block and records the edit; out of bounds writes do nothing and return air.
+    pub fn set_block(&mut self, pos: IVec3, block: Block) -> core::result::Result<Block, WorldFull> {
+        let pos = self.wrap_pos(pos);
+        if !self.in_bounds(pos) {
+            return Ok(Block::AIR);
+        }
+        let old = self.write_block(pos, block)?;
+        self.edits.push(Edit { pos, old, new: block });
+        self.relight_near(pos, pos);
+        Ok(old)
+    }
+
+    /// The last `EDIT_LOG_LEN` edits made through `set_block`, newest first
+    pub fn recent_edits(&self) -> impl Iterator<Item = &Edit> + '_ {
+        self.edits.iter()
+    }
+
+    /// Writes a batch like per-item `write_block` calls would (out of bounds items are skipped,
+    /// later duplicates win), but sorts it by Morton code once and merges it in a single pass
+    /// over the existing blocks instead of scanning them for every item.
+    /// When the new blocks don't fit under `max_blocks`, nothing is written.
+    pub fn insert_many(&mut self, items: &mut [(IVec3, Block)]) -> core::result::Result<(), WorldFull> {
+        items.sort_by_key(|(pos, _)| morton(*pos));
+
+        let mut batch: Vec<(u64, IVec3, Block)> = Vec::with_capacity(items.len());
+        for &(pos, block) in items.iter() {
+            if !self.in_bounds(pos) { continue }
+            let code = morton(pos);
+            match batch.last_mut() {
+                // sort_by_key is stable, so the last duplicate is the latest write
+                Some(last) if last.0 == code => *last = (code, pos, block),
+                _ => batch.push((code, pos, block)),
+            }
+        }
+
+        // Worst case every solid item is new, only then is the exact count worth a search
+        let solid = batch.iter().filter(|b| b.2.id != AIR).count();
+        if self.blocks.len() + solid > self.max_blocks {
+            let new = batch.iter()
+                .filter(|b| b.2.id != AIR && !self.blocks.iter().any(|(p, _)| p.as_ivec3() == b.1))
+                .count();
+            self.check_room(new)?;
+        }
+
+        // Existing blocks the batch touches are overwritten (or dropped for air) in place
+        let mut written = alloc::vec![false; batch.len()];
+        self.blocks.retain_mut(|(pos, block)| {
+            let Ok(i) = batch.binary_search_by_key(&morton(pos.as_ivec3()), |b| b.0) else { return true };
+            written[i] = true;
+            *block = batch[i].2;
+            block.id != AIR
+        });
+        for ((_, pos, block), done) in batch.into_iter().zip(written) {
+            if !done && block.id != AIR {
+                self.blocks.push((pos.as_vec3(), block));
+            }
+            if block.id == AIR {
+                self.meta.set(pos, 0);
+            }
+        }
+        self.rebuild_heightmap();
+        self.recount();
+        Ok(())
+    }
+
+    /// Fails when `new` more blocks would exceed `max_blocks`
+    fn check_room(&self, new: usize) -> core::result::Result<(), WorldFull> {
+        if self.blocks.len() + new > self.max_blocks {
+            return Err(WorldFull { blocks: self.blocks.len(), max_blocks: self.max_blocks });
+        }
+        Ok(())
+    }
+
+    /// Unlogged write used by generation passes
+    fn write_block(&mut self, pos: IVec3, block: Block) -> core::result::Result<Block, WorldFull> {
+        let fpos = pos.as_vec3();
+        let slot = self.blocks.iter().position(|(p, _)| *p == fpos);
+        let old = slot.map_or(Block::AIR, |i| self.blocks[i].1);
+        match slot {
+            Some(i) if block.id == AIR => { self.blocks.swap_remove(i); }
+            Some(i) => self.blocks[i].1 = block,
+            None if block.id != AIR => {
+                self.check_room(1)?;
+                self.blocks.push((fpos, block));
+            }
+            None => {}
+        }
+        // Air is never stored, so it is never counted
+        self.counts[old.id as usize] -= (old.id != AIR) as u32;
+        self.counts[block.id as usize] += (block.id != AIR) as u32;
+        debug_assert_eq!(self.get_block(pos).id, block.id, "set_block round-trip mismatch");
+        self.update_height(pos, block);
+        if block.id != AIR {
+            self.widen_y_span(pos.y);
+        } else if old.id != AIR && self.y_span.is_some_and(|(lo, hi)| pos.y == lo || pos.y == hi) {
+            // An end of the span came off, only a rescan knows where it is now
+            self.recount();
+        }
+        if block.id == AIR {
+            self.meta.set(pos, 0);
+        }
+        Ok(old)
+    }
+
+    /// State byte of the block at `pos`, 0 if it has none
+    pub fn get_meta(&self, pos: IVec3) -> u8 {
+        self.meta.get(pos)
+    }
+
+    /// Attaches a state byte to the block at `pos`, 0 clears it. Ignored where there is no block.
+    pub fn set_meta(&mut self, pos: IVec3, meta: u8) {
+        if meta != 0 && self.get_block(pos).id == AIR { return }
+        self.meta.set(pos, meta);
+    }
+
+    /// `spawn` if set, otherwise two blocks above the highest solid block of the centre column
+    pub fn spawn_point(&self) -> Vec3 {
+        self.spawn.unwrap_or_else(|| {
+            let c = self.size / 2;
+            let top = self.column(c, c).map_or(-1, |i| self.heights[i]);
+            IVec3::new(c, top + 2, c).as_vec3()
+        })
+    }
+
+    /// Whether nothing solid is above `y` in column `x, z`. Outside the footprint is always open.
+    pub fn sky_light(&self, x: i32, z: i32, y: i32) -> bool {
+        self.column(x, z).is_none_or(|i| y > self.heights[i])
+    }
+
+    /// Highest solid y in column `x, z`, `None` for empty columns and outside the footprint
+    pub fn column_top(&self, x: i32, z: i32) -> Option<i32> {
+        self.column(x, z).map(|i| self.heights[i]).filter(|&y| y >= 0)
+    }
+
+    fn column(&self, x: i32, z: i32) -> Option<usize> {
+        ((0..self.size).contains(&x) && (0..self.size).contains(&z)).then(|| (z * self.size + x) as usize)
+    }
+
+    fn update_height(&mut self, pos: IVec3, block: Block) {
+        let Some(i) = self.column(pos.x, pos.z) else { return };
+        self.mark_dirty(IVec2::new(pos.x, pos.z), IVec2::new(pos.x, pos.z));
+        self.heights[i] = self.next_top(pos, block, self.heights[i], |id| block_def(id).solid);
+        self.surfaces[i] = self.next_top(pos, block, self.surfaces[i], |id| id != AIR);
+    }
+
+    /// Top of `pos`'s column among the blocks `counts` accepts, once `block` is written at `pos`
+    fn next_top(&self, pos: IVec3, block: Block, top: i32, counts: impl Fn(u8) -> bool) -> i32 {
+        if counts(block.id) {
+            top.max(pos.y)
+        } else if pos.y == top {
+            // The top came off, the next one down is wherever the column's highest such block is
+            self.blocks.iter()
+                .filter(|(p, b)| p.x as i32 == pos.x && p.z as i32 == pos.z && counts(b.id))
+                .map(|(p, _)| p.y as i32)
+                .max()
+                .unwrap_or(-1)
+        } else {
+            top
+        }
+    }
+
+    /// Recomputes every column of the heightmaps from the block list
+    pub fn rebuild_heightmap(&mut self) {
+        self.heights.fill(-1);
+        self.surfaces.fill(-1);
+        self.mark_dirty(IVec2::ZERO, IVec2::splat(self.size - 1));
+        for i in 0..self.blocks.len() {
+            let (pos, block) = self.blocks[i];
+            let Some(c) = self.column(pos.x as i32, pos.z as i32) else { continue };
+            self.surfaces[c] = self.surfaces[c].max(pos.y as i32);
+            if block_def(block.id).solid {
+                self.heights[c] = self.heights[c].max(pos.y as i32);
+            }
+        }
+    }
+
+    fn mark_dirty(&mut self, min: IVec2, max: IVec2) {
+        self.dirty_columns = Some(self.dirty_columns.map_or((min, max), |(lo, hi)| (lo.min(min), hi.max(max))));
     }
 
-    pub fn add_block(&mut self, pos: Vec3, block: Block) {
-        self.blocks.push((pos, block));
+    /// Columns changed since the last call as an inclusive x/z box, `None` if nothing changed
+    pub fn take_dirty_columns(&mut self) -> Option<(IVec2, IVec2)> {
+        self.dirty_columns.take()
     }
 
-    pub fn generate_example(&mut self) {
-        // Create a 3x3 floor
-        for x in -1..=1 {
-            for z in -1..=1 {
-                self.add_block(vec3(x as f32, 0.0, z as f32), Block::new(1));
+    /// Fills the inclusive box `min..=max` with `block` as one `insert_many` batch, then relights
+    /// once. Not logged, like the other bulk writes. On `WorldFull` nothing is written.
+    pub fn fill_box(&mut self, min: IVec3, max: IVec3, block: Block) -> core::result::Result<(), WorldFull> {
+        let mut items = Vec::new();
+        // Bounds of the wrapped positions, what actually changes
+        let (mut lo, mut hi) = (IVec3::MAX, IVec3::MIN);
+        for y in min.y..=max.y {
+            for z in min.z..=max.z {
+                for x in min.x..=max.x {
+                    let pos = self.wrap_pos(IVec3::new(x, y, z));
+                    (lo, hi) = (lo.min(pos), hi.max(pos));
+                    items.push((pos, block));
+                }
             }
         }
-        // Add a block on top
-        self.add_block(vec3(0.0, 1.0, 0.0), Block::new(2));
+        if items.is_empty() { return Ok(()) }
+        self.insert_many(&mut items)?;
+        self.relight_near(lo, hi);
+        Ok(())
     }
+
+    /// Sets the inclusive box `min..=max` to air in one pass over the block list. Not logged.
+    /// With wrapping on, the box may reach past the footprint like `fill_box`'s does.
+    /// From a running game, call it inside `WorldLock::write` so no core renders a half-cleared world.
+    pub fn clear_region(&mut self, min: IVec3, max: IVec3) {
+        let mut cleared = Vec::new();
+        let blocks = core::mem::take(&mut self.blocks);
+        self.blocks = blocks.into_iter()
+            .filter(|(pos, _)| {
+                let p = pos.as_ivec3();
+                let inside = self.image_in(p, min, max).is_some();
+                if inside { cleared.push(p) }
+                !inside
+            })
+            .collect();
+        let (mut lo, mut hi) = (IVec3::MAX, IVec3::MIN);
+        for pos in cleared {
+            self.meta.set(pos, 0);
+            (lo, hi) = (lo.min(pos), hi.max(pos));
+        }
+        self.rebuild_heightmap();
+        self.recount();
+        if lo.cmple(hi).all() {
+            self.relight_near(lo, hi);
+        }
+    }
+
+    /// Drops every block and generates a fresh `kind` world of the same size, bounds and wrap mode.
+    /// Same locking rule as `clear_region`.
+    pub fn reset(&mut self, kind: WorldKind) -> core::result::Result<(), WorldFull> {
+        let lit = !self.light.is_empty();
+        *self = Self {
+            bounds: self.bounds,
+            wrap: self.wrap,
+            max_blocks: self.max_blocks,
+            sea_level: self.sea_level,
+            ..Self::with_size(self.size)
+        };
+        self.generate(kind, |_, _| {})?;
+        if lit {
+            self.relight();
+        }
+        Ok(())
+    }
+
+    /// Base terrain of `kind`. `report(done, total)` is called as the rows of columns are laid out.
+    pub fn generate(&mut self, kind: WorldKind, report: impl FnMut(u32, u32)) -> core::result::Result<(), WorldFull> {
+        match kind {
+            WorldKind::Flat { height } => self.generate_flat(height, report),
+            WorldKind::Terrain { seed } => self.generate_terrain(seed, report),
+            WorldKind::DebugGrid { spacing } => self.generate_debug_grid(spacing, report),
+        }
+    }
+
+    /// Level ground with its grass surface at `height`, one `fill_box` per layer of `column_block`
+    pub fn generate_flat(&mut self, height: i32, mut report: impl FnMut(u32, u32)) -> core::result::Result<(), WorldFull> {
+        let height = height.clamp(0, WORLD_HEIGHT - 1);
+        let layers = [(0, height - 3), ((height - 2).max(0), height - 1), (height, height)];
+        for (i, &(lo, hi)) in layers.iter().enumerate() {
+            report(i as u32, layers.len() as u32);
+            let (min, max) = (IVec3::new(0, lo, 0), IVec3::new(self.size - 1, hi, self.size - 1));
+            self.fill_box(min, max, Block::new(column_block(lo, height, false)))?;
+        }
+        Ok(())
+    }
+
+    /// Single stone blocks every `spacing` voxels through the whole volume
+    pub fn generate_debug_grid(&mut self, spacing: i32, mut report: impl FnMut(u32, u32)) -> core::result::Result<(), WorldFull> {
+        let spacing = spacing.max(1) as usize;
+        let mut items = Vec::new();
+        for y in (0..WORLD_HEIGHT).step_by(spacing) {
+            report(y as u32, WORLD_HEIGHT as u32);
+            for z in (0..self.size).step_by(spacing) {
+                for x in (0..self.size).step_by(spacing) {
+                    items.push((IVec3::new(x, y, z), Block::new(STONE)));
+                }
+            }
+        }
+        self.insert_many(&mut items)
+    }
+
+    /// Rolling hills over the `size`² footprint, same seed gives the same terrain
+    pub fn generate_terrain(&mut self, seed: u64, mut report: impl FnMut(u32, u32)) -> core::result::Result<(), WorldFull> {
+        let mut column_items = Vec::new();
+        for z in 0..self.size {
+            report(z as u32, self.size as u32);
+            for x in 0..self.size {
+                let n = value_noise(seed, x, z, 32) * 0.65
+                    + value_noise(seed ^ 0x5bd1_e995, x, z, 16) * 0.25
+                    + value_noise(seed ^ 0x27d4_eb2f, x, z, 8) * 0.10;
+                let h = TERRAIN_MIN_HEIGHT
+                    + (n * (TERRAIN_MAX_HEIGHT - TERRAIN_MIN_HEIGHT) as f32) as i32;
+
+                // Layers are fixed per y, so anything carved later exposes dirt and stone, not grass
+                let beach = (h - self.sea_level).abs() <= 1;
+                for y in 0..=h.max(self.sea_level) {
+                    let id = if y > h { WATER } else { column_block(y, h, beach) };
+                    column_items.push((IVec3::new(x, y, z), Block::new(id)));
+                }
+            }
+        }
+        // Morton order also leaves neighbouring voxels next to each other in the block list
+        self.insert_many(&mut column_items)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(SAVE_HEADER_LEN + self.blocks.len() * SAVE_BLOCK_LEN);
+        out.extend_from_slice(&SAVE_MAGIC);
+        out.extend_from_slice(&SAVE_VERSION.to_le_bytes());
+        out.extend_from_slice(&(self.size as u32).to_le_bytes());
+        out.extend_from_slice(&(self.blocks.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.save_count.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        let spawn = self.spawn.unwrap_or(Vec3::ZERO);
+        out.extend_from_slice(&(self.spawn.is_some() as u32).to_le_bytes());
+        for v in spawn.to_array() {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        for (pos, block) in &self.blocks {
+            let pos = pos.as_ivec3();
+            out.extend_from_slice(&pos.x.to_le_bytes());
+            out.extend_from_slice(&pos.y.to_le_bytes());
+            out.extend_from_slice(&pos.z.to_le_bytes());
+            out.push(block.id);
+        }
+        let crc = crc32(&out[SAVE_CRC_AT + 4..]);
+        out[SAVE_CRC_AT..SAVE_CRC_AT + 4].copy_from_slice(&crc.to_le_bytes());
+        out
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self> {
+        let u32_at = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+
+        if data.len() < SAVE_HEADER_LEN_V1 || data[..4] != SAVE_MAGIC {
+            throw!(ErrorType::BadWorldData, "not a world save");
+        }
+        let (header_len, size, count, save_count) = match u32_at(4) {
+            1 => (SAVE_HEADER_LEN_V1, DEFAULT_WORLD_SIZE, u32_at(8) as usize, 0),
+            2 if data.len() >= SAVE_HEADER_LEN_V2 => (SAVE_HEADER_LEN_V2, u32_at(8) as i32, u32_at(12) as usize, 0),
+            3 if data.len() >= SAVE_HEADER_LEN_V3 => {
+                (SAVE_HEADER_LEN_V3, u32_at(8) as i32, u32_at(12) as usize, u32_at(16))
+            }
+            SAVE_VERSION if data.len() >= SAVE_HEADER_LEN => {
+                (SAVE_HEADER_LEN, u32_at(8) as i32, u32_at(12) as usize, u32_at(16))
+            }
+            version => throw!(ErrorType::BadWorldData, "unsupported save version {}", version),
+        };
+        if !(1..=MAX_WORLD_SIZE).contains(&size) {
+            throw!(ErrorType::BadWorldData, "invalid world size {}", size);
+        }
+        let expected = count.checked_mul(SAVE_BLOCK_LEN).and_then(|n| n.checked_add(header_len));
+        if expected != Some(data.len()) {
+            throw!(ErrorType::BadWorldData, "expected {} blocks, file is {} bytes", count, data.len());
+        }
+        // Versions before 3 have no checksum
+        if header_len >= SAVE_HEADER_LEN_V3 && crc32(&data[SAVE_CRC_AT + 4..]) != u32_at(SAVE_CRC_AT) {
+            throw!(ErrorType::BadWorldData, "checksum mismatch, save is torn or damaged");
+        }
+
+        let mut world = Self::with_size(size);
+        let mut blocks = Vec::with_capacity(count);
+        let (records, _) = data[header_len..].as_chunks::<SAVE_BLOCK_LEN>();
+        for rec in records {
+            let i32_at = |i: usize| i32::from_le_bytes([rec[i], rec[i + 1], rec[i + 2], rec[i + 3]]);
+            let id = rec[12];
+            if id == AIR {
+                throw!(ErrorType::BadWorldData, "air block stored in save");
+            }
+            let pos = IVec3::new(i32_at(0), i32_at(4), i32_at(8));
+            // Damage the checksum missed, or a pre-checksum save, would index past the column caches
+            if !world.in_bounds(pos) {
+                throw!(ErrorType::BadWorldData, "block at {} outside the {} wide world", pos, size);
+            }
+            blocks.push((pos.as_vec3(), Block::new(id)));
+        }
+        world.blocks = blocks;
+        world.rebuild_heightmap();
+        world.recount();
+        world.save_count = save_count;
+        if header_len == SAVE_HEADER_LEN && u32_at(24) != 0 {
+            let f32_at = |i: usize| f32::from_bits(u32_at(i));
+            world.spawn = Some(vec3(f32_at(28), f32_at(32), f32_at(36)));
+        }
+        Ok(world)
+    }
+
+    /// Writes the world to `path` on the boot volume, creating parent directories.
+    /// The data goes to `<path>.tmp` first and the previous save is kept as `<path>.bak`,
+    /// so a save cut short leaves at least one intact file behind.
+    pub fn save_to_file(&mut self, path: &CStr16) -> Result {
+        let mut fs = image_fs()?;
+        if let Some(dir) = Path::new(path).parent() {
+            t!(fs.create_dir_all(&dir));
+        }
+        let tmp = sibling_path(path, cstr16!(".tmp"));
+        let bak = sibling_path(path, cstr16!(".bak"));
+
+        self.save_count = self.save_count.wrapping_add(1);
+        t!(fs.write(&*tmp, self.serialize()));
+        if t!(fs.try_exists(path)) {
+            t!(fs.rename(path, &*bak));
+        }
+        t!(fs.rename(&*tmp, path));
+        OK
+    }
+
+    /// Loads `path`, falling back to `<path>.bak` when it is missing or fails its checks.
+    /// Rejected files are reported on `scr`; `Ok(None)` means neither file was usable.
+    pub fn load_from_file(path: &CStr16, scr: &mut Screen) -> Result<Option<Self>> {
+        let mut fs = image_fs()?;
+        for file in [CString16::from(path), sibling_path(path, cstr16!(".bak"))] {
+            if !t!(fs.try_exists(&*file)) { continue }
+            let data = t!(fs.read(&*file));
+            match Self::deserialize(&data) {
+                Ok(world) => return Ok(Some(world)),
+                Err(e) => scr.warn(&format!("Ignoring save {}: {}", file, e.info.unwrap_or_default()))?,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Imports an RLE region at `offset`: header (sx, sy, sz: u16), then (id: u8, run: u16) pairs in YZX order.
+    /// Written as one `insert_many` batch like `fill_box`, so either all of it lands or, on `WorldFull`, none.
+    pub fn import_rle(&mut self, data: &[u8], offset: IVec3) -> Result {
+        let u16_at = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+
+        if data.len() < RLE_HEADER_LEN || !(data.len() - RLE_HEADER_LEN).is_multiple_of(RLE_RUN_LEN) {
+            throw!(ErrorType::BadWorldData, "truncated RLE stream");
+        }
+        let size = IVec3::new(u16_at(0) as i32, u16_at(2) as i32, u16_at(4) as i32);
+        let volume = size.x as usize * size.y as usize * size.z as usize;
+        let (runs, _) = data[RLE_HEADER_LEN..].as_chunks::<RLE_RUN_LEN>();
+
+        // Validate everything before touching the world
+        let total: usize = runs.iter().map(|r| u16::from_le_bytes([r[1], r[2]]) as usize).sum();
+        if total != volume || runs.iter().any(|r| r[1] == 0 && r[2] == 0) {
+            throw!(ErrorType::BadWorldData, "RLE covers {} voxels, region {:?} has {}", total, size, volume);
+        }
+
+        let mut items = Vec::with_capacity(volume);
+        for run in runs {
+            let block = Block::new(run[0]);
+            for _ in 0..u16::from_le_bytes([run[1], run[2]]) {
+                let i = items.len() as i32;
+                let local = IVec3::new(i % size.x, i / (size.x * size.z), i / size.x % size.z);
+                items.push((self.wrap_pos(offset + local), block));
+            }
+        }
+        t!(self.insert_many(&mut items));
+        self.relight_near(offset, offset + size - IVec3::ONE);
+        OK
+    }
+
+    /// Exports the inclusive box `min..=max` in the `import_rle` format, read in one `to_dense` pass
+    pub fn export_rle(&self, min: IVec3, max: IVec3) -> Vec<u8> {
+        let size = (max - min + IVec3::ONE).max(IVec3::ZERO);
+        let mut out = Vec::new();
+        for axis in [size.x, size.y, size.z] {
+            out.extend_from_slice(&(axis as u16).to_le_bytes());
+        }
+
+        let mut push_run = |id: u8, len: u16| {
+            out.push(id);
+            out.extend_from_slice(&len.to_le_bytes());
+        };
+        let mut run: Option<(u8, u16)> = None;
+        for id in self.to_dense(min, max) {
+            run = match run {
+                Some((cur, len)) if cur == id && len < u16::MAX => Some((cur, len + 1)),
+                Some((cur, len)) => { push_run(cur, len); Some((id, 1)) }
+                None => Some((id, 1)),
+            };
+        }
+        if let Some((id, len)) = run {
+            push_run(id, len);
+        }
+        out
+    }
+
+    /// Writes every column from y=0 up to the highest block to `path` on the boot volume as
+    /// `export_rle`, for editing offline and placing again as a structure
+    pub fn export_rle_to_file(&self, path: &CStr16) -> Result {
+        let mut fs = image_fs()?;
+        if let Some(dir) = Path::new(path).parent() {
+            t!(fs.create_dir_all(&dir));
+        }
+        let top = self.y_span.map_or(0, |(_, hi)| hi);
+        t!(fs.write(path, self.export_rle(IVec3::ZERO, IVec3::new(self.size - 1, top, self.size - 1))));
+        OK
+    }
+
+    /// Builds a world from a dense x-fastest, then z, then y array of block ids covering `dims`
+    /// from the origin. The footprint grows to fit `dims`. Test fixtures only.
+    #[cfg(test)]
+    pub fn from_dense(dims: IVec3, data: &[u8]) -> Result<Self> {
+        let volume = dims.x.max(0) as usize * dims.y.max(0) as usize * dims.z.max(0) as usize;
+        if data.len() != volume || dims.y > WORLD_HEIGHT || dims.x.max(dims.z) > MAX_WORLD_SIZE {
+            throw!(ErrorType::BadWorldData, "dense array of {} ids doesn't fit {:?}", data.len(), dims);
+        }
+        let mut world = Self::with_size(dims.x.max(dims.z).max(1));
+        let mut items: Vec<(IVec3, Block)> = data.iter().enumerate()
+            .filter(|(_, id)| **id != AIR)
+            .map(|(i, &id)| {
+                let i = i as i32;
+                (IVec3::new(i % dims.x, i / (dims.x * dims.z), i / dims.x % dims.z), Block::new(id))
+            })
+            .collect();
+        t!(world.insert_many(&mut items));
+        Ok(world)
+    }
+
+    /// The inclusive box `min..=max` as a dense array in `from_dense` order, reading exactly
+    /// what `get_block` would, in one pass over the block list
+    pub fn to_dense(&self, min: IVec3, max: IVec3) -> Vec<u8> {
+        let size = (max - min + IVec3::ONE).max(IVec3::ZERO);
+        let index = |p: IVec3| {
+            let local = p - min;
+            (local.x + size.x * (local.z + size.z * local.y)) as usize
+        };
+        let mut out = alloc::vec![AIR; (size.x * size.y * size.z) as usize];
+        if out.is_empty() { return out }
+
+        for (pos, block) in self.iter_blocks() {
+            if let Some(p) = self.image_in(pos, min, max) {
+                out[index(p)] = block.id;
+            }
+        }
+        if self.bounds == WorldBounds::Bedrock {
+            for y in min.y..=max.y.min(-1) {
+                let row = index(IVec3::new(min.x, y, min.z));
+                out[row..row + (size.x * size.z) as usize].fill(BEDROCK);
+            }
+        }
+        out
+    }
+
+    /// Nearest non-transparent block along the ray within `max_dist`. `dir` must be normalized.
+    /// A ray starting inside a block hits it at distance 0.
+    pub fn raycast(&self, origin: Vec3, dir: Vec3, max_dist: f32) -> Option<RayHit> {
+        let max_dist = if self.wrap == WrapMode::Off { max_dist } else { max_dist.min(WRAP_RAY_DIST) };
+        let (nearest, _) = self.raycast_blocks(origin, dir, max_dist);
+        self.raycast_bedrock(origin, dir, max_dist, nearest)
+    }
+
+    /// Block intersection tests `raycast` had run by the time it found the block it hits, all
+    /// of them for a ray that hits none. What the traversal heatmap shows.
+    pub fn ray_tests(&self, origin: Vec3, dir: Vec3, max_dist: f32) -> usize {
+        let max_dist = if self.wrap == WrapMode::Off { max_dist } else { max_dist.min(WRAP_RAY_DIST) };
+        self.raycast_blocks(origin, dir, max_dist).1
+    }
+
+    /// Intersection tests one pass over the block list does, the most a ray that doesn't wrap can need
+    pub fn ray_tests_per_pass(&self) -> usize {
+        self.counts.iter().enumerate()
+            .filter(|&(id, _)| !block_def(id as u8).transparent)
+            .map(|(_, &n)| n as usize)
+            .sum()
+    }
+
+    /// Nearest stored opaque block along the ray, and the number of tests run up to finding it
+    fn raycast_blocks(&self, origin: Vec3, dir: Vec3, max_dist: f32) -> (Option<RayHit>, usize) {
+        // Bedrock is all that's left, and it is only ever hit going down
+        if self.leaves_y_span(origin, dir) { return (None, 0) }
+
+        let mut nearest: Option<RayHit> = None;
+        let (mut tests, mut found_at) = (0, 0);
+        for offset in self.ray_copies(origin, dir, max_dist) {
+            for (block_pos, block) in &self.blocks {
+                if block_def(block.id).transparent { continue }
+                tests += 1;
+                let center = *block_pos + offset;
+                let min = center - Vec3::splat(0.5);
+                let max = center + Vec3::splat(0.5);
+                let Some((dist, _, normal)) = ray_aabb_intersect(origin, dir, min, max) else { continue };
+
+                if dist <= max_dist && nearest.is_none_or(|n| dist < n.dist) {
+                    nearest = Some(RayHit { pos: block_pos.as_ivec3(), block: *block, normal, dist });
+                    found_at = tests;
+                }
+            }
+        }
+        (nearest, if nearest.is_some() { found_at } else { tests })
+    }
+
+    /// `nearest`, or bedrock where the ray meets it first.
+    /// Bedrock is an infinite slab whose top face is the y=-0.5 plane.
+    fn raycast_bedrock(&self, origin: Vec3, dir: Vec3, max_dist: f32, nearest: Option<RayHit>) -> Option<RayHit> {
+        if self.bounds != WorldBounds::Bedrock || (dir.y >= 0.0 && origin.y >= -0.5) {
+            return nearest;
+        }
+        let dist = if origin.y < -0.5 { 0.0 } else { (-0.5 - origin.y) / dir.y };
+        if dist > max_dist || nearest.is_some_and(|n| dist >= n.dist) {
+            return nearest;
+        }
+        let p = (origin + dir * dist + Vec3::splat(0.5)).floor().as_ivec3();
+        let normal = if dist == 0.0 { IVec3::ZERO } else { IVec3::Y };
+        Some(RayHit { pos: IVec3::new(p.x, -1, p.z), block: Block::new(BEDROCK), normal, dist })
+    }
+
+    /// Whether the ray starts above every stored block without heading down, or below all of
+    /// them without heading up. Wrapped copies only shift in x/z, so they can't change that.
+    fn leaves_y_span(&self, origin: Vec3, dir: Vec3) -> bool {
+        let Some((lo, hi)) = self.y_span else { return true };
+        (origin.y > hi as f32 + 0.5 && dir.y >= 0.0) || (origin.y < lo as f32 - 0.5 && dir.y <= 0.0)
+    }
+
+    /// The first `N` transparent blocks the ray passes through before `max_dist`, nearest
+    /// first, for whatever they do to the colour behind them
+    pub fn crossings<const N: usize>(&self, origin: Vec3, dir: Vec3, max_dist: f32) -> Crossings<N> {
+        let mut crossings = Crossings::new();
+        if N == 0 || self.leaves_y_span(origin, dir) { return crossings }
+        for offset in self.ray_copies(origin, dir, max_dist) {
+            for (pos, block) in &self.blocks {
+                if !block_def(block.id).transparent { continue }
+                let center = *pos + offset;
+                let hit = ray_aabb_intersect(origin, dir, center - Vec3::splat(0.5), center + Vec3::splat(0.5));
+                let Some((near, far, normal)) = hit else { continue };
+                let far = far.min(max_dist);
+                if far > near {
+                    crossings.insert(Crossing { pos: pos.as_ivec3(), block: *block, normal, near, far });
+                }
+            }
+        }
+        crossings
+    }
+
+    /// Places a structure blob: (sx, sy, sz: u16) header, then one block id per voxel in YZX order.
+    /// `STRUCT_KEEP` voxels are skipped and anything outside the world is clipped.
+    pub fn place_structure(&mut self, data: &[u8], origin: IVec3) -> Result {
+        if data.len() < STRUCT_HEADER_LEN {
+            throw!(ErrorType::BadWorldData, "truncated structure header");
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]) as i32;
+        let size = IVec3::new(u16_at(0), u16_at(2), u16_at(4));
+        let volume = size.x as usize * size.y as usize * size.z as usize;
+        if data.len() - STRUCT_HEADER_LEN != volume {
+            throw!(ErrorType::BadWorldData, "structure {:?} needs {} voxels, got {}", size, volume, data.len() - STRUCT_HEADER_LEN);
+        }
+
+        let template: Vec<(IVec3, u8)> = data[STRUCT_HEADER_LEN..].iter().enumerate()
+            .filter(|(_, id)| **id != STRUCT_KEEP)
+            .map(|(i, &id)| {
+                let i = i as i32;
+                (IVec3::new(i % size.x, i / (size.x * size.z), i / size.x % size.z), id)
+            })
+            .collect();
+        t!(self.place_template(&template, origin, true));
+        OK
+    }
+
+    /// Places every `*.bin` structure blob and `*.rle` region in `dir` whose file stem is listed
+    /// in `origins`, returns how many. A missing directory just means no structures.
+    pub fn load_structures(&mut self, dir: &CStr16, origins: &[(&str, IVec3)]) -> Result<u32> {
+        let mut fs = image_fs()?;
+        if !t!(fs.try_exists(dir)) {
+            return Ok(0);
+        }
+
+        let mut placed = 0;
+        for entry in t!(fs.read_dir(dir)) {
+            let Ok(info) = entry else { continue };
+            if info.is_directory() { continue }
+            let name = info.file_name().to_string();
+            let (stem, rle) = match (name.strip_suffix(".bin"), name.strip_suffix(".rle")) {
+                (Some(stem), _) => (stem, false),
+                (_, Some(stem)) => (stem, true),
+                _ => continue,
+            };
+            let Some(&(_, origin)) = origins.iter().find(|(n, _)| *n == stem) else { continue };
+
+            let Ok(path) = CString16::try_from(format!("{}\\{}", dir, name).as_str()) else { continue };
+            let data = t!(fs.read(&*path));
+            if rle {
+                self.import_rle(&data, origin)?;
+            } else {
+                self.place_structure(&data, origin)?;
+            }
+            placed += 1;
+        }
+        Ok(placed)
+    }
+
+    /// Topmost non-air block of column `x, z`, water included, `None` for empty columns and outside
+    /// the footprint (after wrapping). Unlike `column_top`, a lake's surface is its water.
+    pub fn surface_height(&self, x: i32, z: i32) -> Option<i32> {
+        let p = self.wrap_pos(IVec3::new(x, 0, z));
+        self.column(p.x, p.z).map(|i| self.surfaces[i]).filter(|&y| y >= 0)
+    }
+
+    /// Scatters random-walk ore blobs of `ORE_CLUSTER_MIN..=ORE_CLUSTER_MAX` steps through the stone,
+    /// at the per-chunk counts of `ORES`. Steps that land on anything but stone place nothing.
+    /// Returns how many ore blocks were placed.
+    pub fn scatter_ores(&mut self, seed: u64) -> core::result::Result<u32, WorldFull> {
+        let mut placed = 0;
+        let chunks = (self.size + ORE_CHUNK - 1) / ORE_CHUNK;
+
+        for (o, ore) in ORES.iter().enumerate() {
+            for chunk in 0..chunks * chunks {
+                for cluster in 0..ore.clusters_per_chunk {
+                    let mut rng = mix64(seed ^ ((o as u64) << 48) ^ ((chunk as u64) << 16) ^ cluster as u64);
+                    let mut next = |n: u32| {
+                        rng = mix64(rng);
+                        (rng >> 32) as u32 % n
+                    };
+                    let mut pos = IVec3::new(
+                        chunk % chunks * ORE_CHUNK + next(ORE_CHUNK as u32) as i32,
+                        next(ore.max_y as u32 + 1) as i32,
+                        chunk / chunks * ORE_CHUNK + next(ORE_CHUNK as u32) as i32,
+                    );
+                    let steps = ORE_CLUSTER_MIN + next(ORE_CLUSTER_MAX - ORE_CLUSTER_MIN + 1);
+                    for _ in 0..steps {
+                        if self.in_bounds(pos) && self.get_block(pos).id == STONE {
+                            self.write_block(pos, Block::new(ore.id))?;
+                            placed += 1;
+                        }
+                        pos += NEIGHBORS6[next(6) as usize];
+                    }
+                }
+            }
+        }
+        Ok(placed)
+    }
+
+    /// Scatters trees on grass, `density` is the chance per column. Returns how many were planted.
+    /// `report(done, total)` is called for every row of columns.
+    pub fn plant_trees(
+        &mut self, seed: u64, density: f32, mut report: impl FnMut(u32, u32),
+    ) -> core::result::Result<u32, WorldFull> {
+        let seed = seed ^ 0x7ee5_7ee5;
+        let mut planted = 0;
+
+        for z in 0..self.size {
+            report(z as u32, self.size as u32);
+            for x in 0..self.size {
+                if hash2(seed, x, z) >= density { continue }
+                let Some(ground) = self.surface_height(x, z) else { continue };
+                if self.get_block(IVec3::new(x, ground, z)).id != GRASS { continue }
+
+                let trunk = 4 + (hash2(seed ^ 1, x, z) * 3.0) as i32;
+                let top = ground + trunk;
+
+                // Keep canopies apart: the 5x5 ring around ours must be free of other trees
+                let crowded = (top..=top + 1).any(|y| {
+                    (z - 2..=z + 2).any(|cz| (x - 2..=x + 2).any(|cx| {
+                        matches!(self.get_block(IVec3::new(cx, y, cz)).id, WOOD | LEAVES)
+                    }))
+                });
+                if crowded { continue }
+
+                // Upright, the axis a log has without meta
+                for y in ground + 1..=top {
+                    self.write_block(IVec3::new(x, y, z), Block::new(WOOD))?;
+                }
+                for y in top..=top + 1 {
+                    for cz in z - 1..=z + 1 {
+                        for cx in x - 1..=x + 1 {
+                            let outside = cx < 0 || cz < 0 || cx >= self.size || cz >= self.size;
+                            if outside || (cx == x && cz == z && y == top) { continue }
+                            self.write_block(IVec3::new(cx, y, cz), Block::new(LEAVES))?;
+                        }
+                    }
+                }
+                planted += 1;
+            }
+        }
+
+        Ok(planted)
+    }
+}
+
+/// Slab test. Returns the entry distance (0 when starting inside), the exit distance and the entry face normal.
+/// Zero direction components are handled explicitly so no 0/0 NaN can appear.
+fn ray_aabb_intersect(origin: Vec3, dir: Vec3, min: Vec3, max: Vec3) -> Option<(f32, f32, IVec3)> {
+    let mut tmin = f32::NEG_INFINITY;
+    let mut tmax = f32::INFINITY;
+    let mut normal = IVec3::ZERO;
+
+    for axis in 0..3 {
+        let (o, d) = (origin[axis], dir[axis]);
+        if d == 0.0 {
+            if o < min[axis] || o > max[axis] { return None }
+            continue;
+        }
+        let t1 = (min[axis] - o) / d;
+        let t2 = (max[axis] - o) / d;
+        let (near, far) = if t1 < t2 { (t1, t2) } else { (t2, t1) };
+
+        if near > tmin {
+            tmin = near;
+            normal = IVec3::ZERO;
+            normal[axis] = if d > 0.0 { -1 } else { 1 };
+        }
+        tmax = tmax.min(far);
+    }
+
+    if tmax < 0.0 || tmin > tmax {
+        return None;
+    }
+    if tmin < 0.0 {
+        return Some((0.0, tmax, IVec3::ZERO));
+    }
+    Some((tmin, tmax, normal))
+}
+
+/// A quarter of the free conventional memory, in blocks. The list can briefly need about
+/// three times its length while a `Vec` grows, which this leaves room for.
+#[cfg(target_os = "uefi")]
+fn default_max_blocks() -> usize {
+    use uefi::boot::{memory_map, MemoryType, PAGE_SIZE};
+    use uefi::mem::memory_map::MemoryMap;
+
+    let free: usize = memory_map(MemoryType::LOADER_DATA).map_or(0, |map| {
+        map.entries()
+            .filter(|d| d.ty == MemoryType::CONVENTIONAL)
+            .map(|d| d.page_count as usize * PAGE_SIZE)
+            .sum()
+    });
+    match free {
+        0 => usize::MAX,
+        free => free / 4 / size_of::<(Vec3, Block)>(),
+    }
+}
+
+/// Host test builds have no boot services to ask, so nothing caps them
+#[cfg(not(target_os = "uefi"))]
+fn default_max_blocks() -> usize {
+    usize::MAX
+}
+
+/// Simple File System of the volume this image was loaded from
+pub(crate) fn image_fs() -> Result<FileSystem> {
+    Ok(FileSystem::new(t!(get_image_file_system(image_handle()))))
+}
+
+/// `path` with `suffix` appended, e.g. `world.dat` -> `world.dat.bak`
+fn sibling_path(path: &CStr16, suffix: &CStr16) -> CString16 {
+    let mut out = CString16::from(path);
+    out.push_str(suffix);
+    out
+}
+
+/// CRC-32 (IEEE, reflected) of `data`
+fn crc32(data: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut c = i as u32;
+            let mut k = 0;
+            while k < 8 {
+                c = if c & 1 != 0 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+                k += 1;
+            }
+            table[i] = c;
+            i += 1;
+        }
+        table
+    };
+    !data.iter().fold(!0u32, |c, &b| TABLE[((c ^ b as u32) & 0xff) as usize] ^ (c >> 8))
+}
+
+/// Seed from EFI_RNG_PROTOCOL, or TSC mixed with the RTC when the firmware has no RNG
+pub fn random_seed() -> u64 {
+    let from_rng = || -> uefi::Result<u64> {
+        let handle = get_handle_for_protocol::<Rng>()?;
+        let mut rng = open_protocol_exclusive::<Rng>(handle)?;
+        let mut buf = [0u8; 8];
+        rng.get_rng(None, &mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    };
+    if let Ok(seed) = from_rng() { return seed }
+
+    // OVMF without virtio-rng lands here
+    let tsc = unsafe { _rdtsc() };
+    let rtc = runtime::get_time().map_or(0, |t| {
+        let date = (t.year() as u64) << 40 | (t.month() as u64) << 32 | (t.day() as u64) << 24
+            | (t.hour() as u64) << 16 | (t.minute() as u64) << 8 | t.second() as u64;
+        date ^ (t.nanosecond() as u64) << 20
+    });
+    mix64(tsc ^ mix64(rtc))
+}
+
+/// Interleaves the low 21 bits of each coordinate, x lowest
+fn morton(p: IVec3) -> u64 {
+    fn spread(v: i32) -> u64 {
+        let mut v = v as u32 as u64 & 0x1f_ffff;
+        v = (v | v << 32) & 0x001f_0000_0000_ffff;
+        v = (v | v << 16) & 0x001f_0000_ff00_00ff;
+        v = (v | v << 8) & 0x100f_00f0_0f00_f00f;
+        v = (v | v << 4) & 0x10c3_0c30_c30c_30c3;
+        (v | v << 2) & 0x1249_2492_4924_9249
+    }
+    spread(p.x) | spread(p.y) << 1 | spread(p.z) << 2
+}
+
+/// splitmix64 finalizer
+fn mix64(mut h: u64) -> u64 {
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
+
+/// Face neighbour offsets in `neighbor_mask6` bit order
+pub const NEIGHBORS6: [IVec3; 6] = [IVec3::X, IVec3::NEG_X, IVec3::Y, IVec3::NEG_Y, IVec3::Z, IVec3::NEG_Z];
+
+/// Bit of offset `d` (each axis in -1..=1) in a 3x3x3 `query_aabb_solid_mask`
+fn cube_bit(d: IVec3) -> u64 {
+    let l = d + IVec3::ONE;
+    1 << (l.x + 3 * (l.z + 3 * l.y))
+}
+
+/// Side of the square column area ore counts are given per
+const ORE_CHUNK: i32 = 16;
+const ORE_CLUSTER_MIN: u32 = 3;
+const ORE_CLUSTER_MAX: u32 = 8;
+
+/// One ore type for `World::scatter_ores`
+pub struct OreSpec {
+    pub id: u8,
+    /// Blobs started per `ORE_CHUNK` square, some steps miss the stone so it is approximate
+    pub clusters_per_chunk: u32,
+    /// Blobs start at or below this y
+    pub max_y: i32,
+}
+
+pub const ORES: &[OreSpec] = &[
+    OreSpec { id: COAL, clusters_per_chunk: 6, max_y: 14 },
+    OreSpec { id: IRON, clusters_per_chunk: 3, max_y: 8 },
+];
+
+/// Layering of a generated column whose surface is at `height`: grass over two dirt over stone.
+/// Beach columns get sand in place of the grass and dirt.
+fn column_block(y: i32, height: i32, beach: bool) -> u8 {
+    if y < height - 2 { STONE } else if beach { SAND } else if y == height { GRASS } else { DIRT }
+}
+
+/// Readers-writer spinlock around the world shared with the AP cores.
+///
+/// Render cores only ever `read`, once per tile, so readers never wait for long.
+/// `write` is for core 0 between frames: it waits for every reader to leave and
+/// keeps new readers out until the edit is done.
+pub struct WorldLock {
+    /// Number of active readers, `WRITER` set while a writer holds the lock
+    state: AtomicUsize,
+    world: UnsafeCell<World>,
+}
+
+// Access to the inner world is serialized by `state`
+unsafe impl Sync for WorldLock {}
+
+const WRITER: usize = 1 << (usize::BITS - 1);
+
+impl WorldLock {
+    pub fn new(world: World) -> Self {
+        Self { state: AtomicUsize::new(0), world: UnsafeCell::new(world) }
+    }
+
+    pub fn read<R>(&self, f: impl FnOnce(&World) -> R) -> R {
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & WRITER == 0 && self.state.compare_exchange_weak(
+                state, state + 1, Ordering::Acquire, Ordering::Relaxed
+            ).is_ok() { break }
+            spin_loop();
+        }
+        let ret = f(unsafe { &*self.world.get() });
+        self.state.fetch_sub(1, Ordering::Release);
+        ret
+    }
+
+    /// Core 0 only, between frames
+    pub fn write<R>(&self, f: impl FnOnce(&mut World) -> R) -> R {
+        // Claim the writer bit first so no new readers get in, then drain the existing ones
+        while self.state.fetch_or(WRITER, Ordering::Acquire) & WRITER != 0 {
+            spin_loop();
+        }
+        while self.state.load(Ordering::Acquire) != WRITER {
+            spin_loop();
+        }
+        let ret = f(unsafe { &mut *self.world.get() });
+        self.state.store(0, Ordering::Release);
+        ret
+    }
+}
+
+/// Lattice hash -> [0, 1)
+fn hash2(seed: u64, x: i32, z: i32) -> f32 {
+    let h = mix64(seed
+        ^ (x as u32 as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+        ^ (z as u32 as u64).wrapping_mul(0xc2b2_ae3d_27d4_eb4f));
+    (h >> 40) as f32 / (1u64 << 24) as f32
+}
+
+/// 2D value noise with lattice spacing `cell`, integer-only lattice lookup so no libm floor is needed
+pub(crate) fn value_noise(seed: u64, x: i32, z: i32, cell: i32) -> f32 {
+    let (cx, cz) = (x.div_euclid(cell), z.div_euclid(cell));
+    let fx = x.rem_euclid(cell) as f32 / cell as f32;
+    let fz = z.rem_euclid(cell) as f32 / cell as f32;
+    // smoothstep
+    let (sx, sz) = (fx * fx * (3.0 - 2.0 * fx), fz * fz * (3.0 - 2.0 * fz));
+
+    let a = hash2(seed, cx, cz);
+    let b = hash2(seed, cx + 1, cz);
+    let c = hash2(seed, cx, cz + 1);
+    let d = hash2(seed, cx + 1, cz + 1);
+
+    let top = a + (b - a) * sx;
+    let bottom = c + (d - c) * sx;
+    top + (bottom - top) * sz
 }
@@ -0,0 +1,80 @@
+use glam::{IVec3, Vec3};
+
+/// Block id 0 is air: it is never stored, writing it removes the block
+pub const AIR: u8 = 0;
+pub const STONE: u8 = 1;
+pub const BRICK: u8 = 2;
+pub const DIRT: u8 = 3;
+pub const GRASS: u8 = 4;
+pub const WOOD: u8 = 5;
+pub const LEAVES: u8 = 6;
+/// Unbreakable floor returned below y=0 under `WorldBounds::Bedrock`
+pub const BEDROCK: u8 = 7;
+pub const WATER: u8 = 8;
+pub const GLOWSTONE: u8 = 9;
+pub const SAND: u8 = 10;
+pub const COAL: u8 = 11;
+pub const IRON: u8 = 12;
+/// Solid but see-through, tints only where a ray enters it
+pub const GLASS: u8 = 13;
+
+/// Per-type block properties
+pub struct BlockDef {
+    pub name: &'static str,
+    /// Linear color in 0..1
+    pub color: Vec3,
+    /// Blocks movement
+    pub solid: bool,
+    /// Rays pass through it
+    pub transparent: bool,
+    /// Light level it emits, 0 for most blocks
+    pub light: u8,
+    /// Reads a state byte from the world's `MetaPool`
+    pub has_meta: bool,
+}
+
+pub const fn rgb(r: u8, g: u8, b: u8) -> Vec3 {
+    Vec3::new(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0)
+}
+
+const fn def(name: &'static str, color: Vec3, solid: bool, transparent: bool) -> BlockDef {
+    BlockDef { name, color, solid, transparent, light: 0, has_meta: false }
+}
+
+/// Indexed by block id
+static BLOCK_DEFS: [BlockDef; 14] = [
+    def("air", rgb(0, 0, 0), false, true),
+    def("stone", rgb(100, 100, 100), true, false),
+    def("brick", rgb(200, 50, 50), true, false),
+    def("dirt", rgb(120, 85, 55), true, false),
+    def("grass", rgb(80, 160, 60), true, false),
+    BlockDef { has_meta: true, ..def("wood", rgb(110, 80, 45), true, false) },
+    def("leaves", rgb(40, 120, 40), true, false),
+    def("bedrock", rgb(35, 35, 40), true, false),
+    def("water", rgb(40, 90, 200), false, true),
+    BlockDef { light: 15, ..def("glowstone", rgb(250, 220, 120), true, false) },
+    def("sand", rgb(220, 205, 140), true, false),
+    def("coal", rgb(30, 30, 30), true, false),
+    def("iron", rgb(200, 160, 130), true, false),
+    def("glass", rgb(200, 230, 245), true, true),
+];
+
+/// Fallback for ids without a definition, loud on purpose
+static MISSING: BlockDef = def("missing", rgb(255, 0, 255), true, false);
+
+pub fn block_def(id: u8) -> &'static BlockDef {
+    BLOCK_DEFS.get(id as usize).unwrap_or(&MISSING)
+}
+
+/// Meta bytes of logs lying along x and z. Upright logs, like tree trunks, need none.
+pub const LOG_X: u8 = 1;
+pub const LOG_Z: u8 = 2;
+
+/// Axis a log runs along, from its meta byte: `LOG_X`, `LOG_Z`, anything else (including none) is y
+pub fn log_axis(meta: u8) -> IVec3 {
+    match meta {
+        LOG_X => IVec3::X,
+        LOG_Z => IVec3::Z,
+        _ => IVec3::Y,
+    }
+}
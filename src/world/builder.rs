@@ -0,0 +1,113 @@
+use alloc::format;
+use glam::IVec3;
+use uefi::CStr16;
+use crate::error::Result;
+use crate::render::Screen;
+use crate::t;
+use super::{StructureId, World, WorldKind, DEFAULT_WORLD_SIZE, SEA_LEVEL};
+
+/// Generation pipeline for a fresh world. Every stage after the base terrain is optional:
+///
+/// ```ignore
+/// let world = WorldBuilder::new(WorldKind::Flat { height: 4 })
+///     .trees(0.01)
+///     .build(scr)?;
+/// ```
+pub struct WorldBuilder<'a> {
+    kind: WorldKind,
+    size: i32,
+    seed: u64,
+    sea_level: i32,
+    ores: bool,
+    /// Search radius around the centre
+    house: Option<i32>,
+    /// Chance per grass column
+    trees: Option<f32>,
+    structures: Option<(&'a CStr16, &'a [(&'a str, IVec3)])>,
+}
+
+impl<'a> WorldBuilder<'a> {
+    /// Just the base `kind` terrain on a `DEFAULT_WORLD_SIZE` footprint
+    pub fn new(kind: WorldKind) -> Self {
+        Self {
+            kind,
+            size: DEFAULT_WORLD_SIZE,
+            seed: 0,
+            sea_level: SEA_LEVEL,
+            ores: false,
+            house: None,
+            trees: None,
+            structures: None,
+        }
+    }
+
+    pub fn size(mut self, size: i32) -> Self {
+        self.size = size;
+        self
+    }
+
+    /// Seed of the decoration stages, `kind` carries its own
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn sea_level(mut self, sea_level: i32) -> Self {
+        self.sea_level = sea_level;
+        self
+    }
+
+    pub fn ores(mut self, ores: bool) -> Self {
+        self.ores = ores;
+        self
+    }
+
+    /// A starter house on free ground within `radius` columns of the centre
+    pub fn house(mut self, radius: Option<i32>) -> Self {
+        self.house = radius;
+        self
+    }
+
+    pub fn trees(mut self, density: Option<f32>) -> Self {
+        self.trees = density;
+        self
+    }
+
+    /// Structure files from `dir`, see `World::load_structures`
+    pub fn structures(mut self, dir: &'a CStr16, origins: &'a [(&'a str, IVec3)]) -> Self {
+        self.structures = Some((dir, origins));
+        self
+    }
+
+    /// Runs the enabled stages in order, reporting each on `scr` and the long ones on its progress bar
+    pub fn build(self, scr: &mut Screen) -> Result<World> {
+        scr.println(&format!("Generating {:?}", self.kind))?;
+        let mut world = World::with_size(self.size);
+        world.sea_level = self.sea_level;
+        // A bar that fails to draw isn't worth failing generation over
+        t!(world.generate(self.kind, |done, total| { let _ = scr.progress("Terrain", done, total); }));
+
+        if self.ores {
+            let ores = t!(world.scatter_ores(self.seed));
+            scr.println(&format!("Scattered {} ore blocks", ores))?;
+        }
+        if let Some(radius) = self.house {
+            let center = IVec3::new(self.size / 2, 0, self.size / 2);
+            match t!(world.spawn_structure_near(StructureId::House, center, radius)) {
+                Some(at) => scr.println(&format!("Built a house at {}", at))?,
+                None => scr.warn("No room for a house")?,
+            }
+        }
+        if let Some(density) = self.trees {
+            let trees = t!(world.plant_trees(self.seed, density, |done, total| {
+                let _ = scr.progress("Trees", done, total);
+            }));
+            scr.println(&format!("Planted {} trees", trees))?;
+        }
+        if let Some((dir, origins)) = self.structures {
+            let structures = world.load_structures(dir, origins)?;
+            scr.println(&format!("Placed {} structures", structures))?;
+        }
+        Ok(world)
+    }
+}
@@ -0,0 +1,154 @@
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+use glam::IVec3;
+use super::{block_def, World, NEIGHBORS6, WORLD_HEIGHT};
+
+/// Full brightness, what sky-exposed air and glowstone get
+pub const MAX_LIGHT: u8 = 15;
+
+/// 4-bit light level for every voxel of the editable volume, two voxels per byte.
+/// Empty until the first `World::relight`, which reads as fully lit.
+pub struct LightMap {
+    size: i32,
+    data: Vec<u8>,
+}
+
+impl LightMap {
+    pub const fn empty() -> Self {
+        Self { size: 0, data: Vec::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn index(&self, pos: IVec3) -> Option<usize> {
+        let inside = (0..self.size).contains(&pos.x) && (0..self.size).contains(&pos.z)
+            && (0..WORLD_HEIGHT).contains(&pos.y);
+        inside.then(|| ((pos.y * self.size + pos.z) * self.size + pos.x) as usize)
+    }
+
+    /// Outside the volume is open sky
+    pub fn get(&self, pos: IVec3) -> u8 {
+        match self.index(pos) {
+            Some(i) if !self.data.is_empty() => (self.data[i / 2] >> (i % 2 * 4)) & 0xF,
+            _ => MAX_LIGHT,
+        }
+    }
+
+    fn set(&mut self, pos: IVec3, level: u8) {
+        let Some(i) = self.index(pos) else { return };
+        let shift = i % 2 * 4;
+        self.data[i / 2] = (self.data[i / 2] & !(0xF << shift)) | (level << shift);
+    }
+}
+
+/// Dense opaque bitmap of the volume, so the flood fill doesn't search the block list per step
+struct Opacity {
+    size: i32,
+    bits: Vec<u64>,
+    emitters: Vec<(IVec3, u8)>,
+}
+
+impl Opacity {
+    fn build(world: &World) -> Self {
+        let size = world.size;
+        let volume = (size * size * WORLD_HEIGHT) as usize;
+        let mut opacity = Self { size, bits: vec![0; volume.div_ceil(64)], emitters: Vec::new() };
+        for (pos, block) in world.iter_blocks().filter(|(pos, _)| world.in_bounds(*pos)) {
+            let def = block_def(block.id);
+            if !def.transparent {
+                let i = opacity.index(pos);
+                opacity.bits[i / 64] |= 1 << (i % 64);
+            }
+            if def.light > 0 {
+                opacity.emitters.push((pos, def.light));
+            }
+        }
+        opacity
+    }
+
+    fn index(&self, pos: IVec3) -> usize {
+        ((pos.y * self.size + pos.z) * self.size + pos.x) as usize
+    }
+
+    fn opaque(&self, pos: IVec3) -> bool {
+        let i = self.index(pos);
+        self.bits[i / 64] & (1 << (i % 64)) != 0
+    }
+}
+
+impl World {
+    /// Light level of the voxel at `pos`: 0 in the dark, `MAX_LIGHT` under open sky
+    pub fn light_at(&self, pos: IVec3) -> u8 {
+        self.light.get(pos)
+    }
+
+    /// Recomputes light for the whole volume
+    pub fn relight(&mut self) {
+        let volume = (self.size * self.size * WORLD_HEIGHT) as usize;
+        self.light = LightMap { size: self.size, data: vec![0; volume.div_ceil(2)] };
+        self.relight_columns(IVec3::ZERO, IVec3::new(self.size - 1, 0, self.size - 1));
+    }
+
+    /// Recomputes light in every column that light from the box `min..=max` can reach.
+    /// Does nothing before the first `relight`.
+    pub(super) fn relight_near(&mut self, min: IVec3, max: IVec3) {
+        if self.light.is_empty() { return }
+        let reach = IVec3::new(MAX_LIGHT as i32, 0, MAX_LIGHT as i32);
+        let min = (min - reach).max(IVec3::ZERO).with_y(0);
+        let max = (max + reach).min(IVec3::splat(self.size - 1)).with_y(0);
+        if min.x > max.x || min.z > max.z { return }
+        self.relight_columns(min, max);
+    }
+
+    /// Flood fills the full-height columns between `min` and `max` (x/z only, inclusive).
+    /// Light outside the box is kept as is and flows back in from its border.
+    fn relight_columns(&mut self, min: IVec3, max: IVec3) {
+        let opacity = Opacity::build(self);
+        let light = &mut self.light;
+        let inside = |p: IVec3| (min.x..=max.x).contains(&p.x) && (min.z..=max.z).contains(&p.z)
+            && (0..WORLD_HEIGHT).contains(&p.y);
+        let mut queue = VecDeque::new();
+
+        for x in min.x..=max.x {
+            for z in min.z..=max.z {
+                // Sky light goes straight down until the first opaque block
+                let mut sky = true;
+                for y in (0..WORLD_HEIGHT).rev() {
+                    let p = IVec3::new(x, y, z);
+                    sky &= !opacity.opaque(p);
+                    light.set(p, if sky { MAX_LIGHT } else { 0 });
+                    if sky { queue.push_back(p) }
+                }
+            }
+        }
+        for &(p, level) in opacity.emitters.iter().filter(|(p, _)| inside(*p)) {
+            if level > light.get(p) {
+                light.set(p, level);
+                queue.push_back(p);
+            }
+        }
+        // The ring of voxels just outside the box seeds light from its neighbours
+        for x in min.x - 1..=max.x + 1 {
+            for z in min.z - 1..=max.z + 1 {
+                if inside(IVec3::new(x, 0, z)) || !(0..self.size).contains(&x) || !(0..self.size).contains(&z) {
+                    continue;
+                }
+                queue.extend((0..WORLD_HEIGHT).map(|y| IVec3::new(x, y, z)).filter(|p| light.get(*p) > 1));
+            }
+        }
+
+        while let Some(p) = queue.pop_front() {
+            let level = light.get(p);
+            if level <= 1 { continue }
+            for n in NEIGHBORS6.map(|d| p + d) {
+                if inside(n) && !opacity.opaque(n) && light.get(n) < level - 1 {
+                    light.set(n, level - 1);
+                    queue.push_back(n);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use glam::IVec3;
+use super::{Block, World, WorldFull, AIR, BRICK, GLOWSTONE, LOG_X, WOOD};
+
+/// A structure as `(offset from origin, block id)` entries, the same form
+/// `World::place_structure` turns structure files into. Air entries clear space.
+pub type Template = [(IVec3, u8)];
+
+/// Compiled-in structures for `World::spawn_structure`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StructureId {
+    /// 5x5 brick hut with a doorway on -Z and a roof of logs along x with a glowstone in the middle
+    House,
+}
+
+impl StructureId {
+    pub fn template(self) -> &'static Template {
+        match self {
+            StructureId::House => &HOUSE,
+        }
+    }
+
+    /// Meta byte for the template's `id` blocks
+    fn meta(self, id: u8) -> u8 {
+        match self {
+            StructureId::House if id == WOOD => LOG_X,
+            StructureId::House => 0,
+        }
+    }
+}
+
+const HOUSE_SIZE: i32 = 5;
+const HOUSE_WALL: i32 = 3;
+const HOUSE_CELLS: usize = (HOUSE_SIZE * HOUSE_SIZE * (HOUSE_WALL + 1)) as usize;
+static HOUSE: [(IVec3, u8); HOUSE_CELLS] = house();
+
+const fn house() -> [(IVec3, u8); HOUSE_CELLS] {
+    let mut out = [(IVec3::ZERO, AIR); HOUSE_CELLS];
+    let mut n = 0;
+    let mut y = 0;
+    while y <= HOUSE_WALL {
+        let mut z = 0;
+        while z < HOUSE_SIZE {
+            let mut x = 0;
+            while x < HOUSE_SIZE {
+                let edge = x == 0 || z == 0 || x == HOUSE_SIZE - 1 || z == HOUSE_SIZE - 1;
+                let door = z == 0 && x == HOUSE_SIZE / 2 && y < 2;
+                let id = if y == HOUSE_WALL {
+                    if x == HOUSE_SIZE / 2 && z == HOUSE_SIZE / 2 { GLOWSTONE } else { WOOD }
+                } else if edge && !door {
+                    BRICK
+                } else {
+                    AIR
+                };
+                out[n] = (IVec3::new(x, y, z), id);
+                n += 1;
+                x += 1;
+            }
+            z += 1;
+        }
+        y += 1;
+    }
+    out
+}
+
+impl World {
+    /// Places a compiled-in structure at `origin`. Unless `force` is set, nothing is placed
+    /// and false returned when the structure would replace any existing block.
+    pub fn spawn_structure(&mut self, id: StructureId, origin: IVec3, force: bool) -> Result<bool, WorldFull> {
+        if !self.place_template(id.template(), origin, force)? {
+            return Ok(false);
+        }
+        for &(offset, block) in id.template() {
+            self.set_meta(self.wrap_pos(origin + offset), id.meta(block));
+        }
+        Ok(true)
+    }
+
+    /// Tries `spawn_structure` on the ground of each column within `radius` of `center`
+    /// (x/z only), nearest rings first, and returns the origin it was placed at
+    pub fn spawn_structure_near(
+        &mut self, id: StructureId, center: IVec3, radius: i32,
+    ) -> Result<Option<IVec3>, WorldFull> {
+        for ring in 0..=radius {
+            for dz in -ring..=ring {
+                for dx in -ring..=ring {
+                    if dx.abs() != ring && dz.abs() != ring { continue }
+                    let (x, z) = (center.x + dx, center.z + dz);
+                    let Some(ground) = self.surface_height(x, z) else { continue };
+                    // Water is the surface over lakes, nothing to build on
+                    if !self.is_solid(IVec3::new(x, ground, z)) { continue }
+                    let origin = IVec3::new(x, ground + 1, z);
+                    if self.spawn_structure(id, origin, false)? {
+                        return Ok(Some(origin));
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Shared by compiled-in and file structures. Entries outside the world are clipped.
+    /// Fails before writing anything when the solid entries might not fit under `max_blocks`,
+    /// so a full world never ends up with half a structure.
+    pub(super) fn place_template(
+        &mut self, template: &Template, origin: IVec3, force: bool,
+    ) -> Result<bool, WorldFull> {
+        let targets = || template.iter().map(|&(offset, id)| (origin + offset, id));
+        if !force && targets().any(|(pos, _)| self.in_bounds(pos) && self.get_block(pos).id != AIR) {
+            return Ok(false);
+        }
+        let solid = targets().filter(|&(pos, id)| id != AIR && self.in_bounds(self.wrap_pos(pos))).count();
+        self.check_room(solid)?;
+        for (pos, id) in targets() {
+            self.set_block(pos, Block::new(id))?;
+        }
+        Ok(true)
+    }
+}
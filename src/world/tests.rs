@@ -0,0 +1,618 @@
+//! Host-side checks of the world store against a plain dense array, run with
+//! `cargo test --target x86_64-unknown-linux-gnu`
+
+use alloc::vec::Vec;
+use glam::{IVec2, IVec3, Vec3};
+use crate::render::Heightmap;
+use super::*;
+
+/// Edge length of the test volume, small enough to compare every voxel after every step
+const N: i32 = 8;
+/// Ids the random worlds are drawn from, air weighted up so removals are common
+const PALETTE: [u8; 8] = [AIR, AIR, AIR, STONE, DIRT, WATER, GLASS, BRICK];
+
+/// splitmix64 stream, deterministic so a failure reproduces
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        mix64(self.0)
+    }
+
+    fn below(&mut self, n: i32) -> i32 {
+        (self.next() % n as u64) as i32
+    }
+
+    /// Uniform in `lo..hi`
+    fn range(&mut self, lo: f32, hi: f32) -> f32 {
+        lo + (self.next() >> 40) as f32 / (1u64 << 24) as f32 * (hi - lo)
+    }
+
+    fn pos(&mut self) -> IVec3 {
+        IVec3::new(self.below(N), self.below(N), self.below(N))
+    }
+
+    fn id(&mut self) -> u8 {
+        PALETTE[self.below(PALETTE.len() as i32) as usize]
+    }
+
+    fn dir(&mut self) -> Vec3 {
+        loop {
+            let d = Vec3::new(self.range(-1.0, 1.0), self.range(-1.0, 1.0), self.range(-1.0, 1.0));
+            // Axis-parallel rays graze cell edges, the random ones are for everything else
+            if d.abs().min_element() > 1e-3 && d.length() > 0.1 {
+                return d.normalize();
+            }
+        }
+    }
+}
+
+/// Index into an `N`³ dense array in `from_dense` order
+fn index(p: IVec3) -> usize {
+    (p.x + N * (p.z + N * p.y)) as usize
+}
+
+fn open_world() -> World {
+    let mut world = World::with_size(N);
+    world.bounds = WorldBounds::Open;
+    world
+}
+
+fn random_dense(rng: &mut Rng) -> Vec<u8> {
+    (0..N * N * N).map(|_| rng.id()).collect()
+}
+
+/// Every query that reads single voxels agrees with `dense`
+fn assert_matches(world: &World, dense: &[u8]) {
+    assert_eq!(world.to_dense(IVec3::ZERO, IVec3::splat(N - 1)), dense);
+    for y in 0..N {
+        for z in 0..N {
+            for x in 0..N {
+                let p = IVec3::new(x, y, z);
+                assert_eq!(world.get_block(p).id, dense[index(p)], "get_block at {}", p);
+            }
+        }
+    }
+    world.validate().unwrap();
+
+    for z in 0..N {
+        for x in 0..N {
+            let column = |keep: fn(u8) -> bool| (0..N).rev().find(|&y| keep(dense[index(IVec3::new(x, y, z))]));
+            assert_eq!(world.column_top(x, z), column(|id| block_def(id).solid), "column top at {} {}", x, z);
+            assert_eq!(world.surface_height(x, z), column(|id| id != AIR), "surface at {} {}", x, z);
+        }
+    }
+
+    let mut census = [0; 256];
+    for &id in dense.iter().filter(|&&id| id != AIR) {
+        census[id as usize] += 1;
+    }
+    assert_eq!(world.census(), census);
+}
+
+/// `World::raycast` as position and distance, far enough to cross the whole height
+fn world_ray(world: &World, origin: Vec3, dir: Vec3) -> Option<(IVec3, f32)> {
+    world.raycast(origin, dir, WORLD_HEIGHT as f32).map(|h| (h.pos, h.dist))
+}
+
+/// An `N`³ array in `from_dense` order, air outside
+struct DenseWorld(Vec<u8>);
+
+impl BlockSource for DenseWorld {
+    fn get_block(&self, pos: IVec3) -> u8 {
+        let inside = pos.cmpge(IVec3::ZERO).all() && pos.cmplt(IVec3::splat(N)).all();
+        if inside { self.0[index(pos)] } else { AIR }
+    }
+}
+
+/// Cell by cell traversal (Amanatides & Woo) over any `BlockSource`, the reference `raycast` is held to
+fn ray_march(source: &impl BlockSource, origin: Vec3, dir: Vec3, max_dist: f32) -> Option<(IVec3, f32)> {
+    // Cells span `pos - 0.5..pos + 0.5`, shifted here to `pos..pos + 1`
+    let start = origin + 0.5;
+    let mut cell = start.floor().as_ivec3();
+    let step = dir.signum().as_ivec3();
+    let delta = dir.recip().abs();
+    let mut next = Vec3::from_array(core::array::from_fn(|a| {
+        let edge = (cell[a] + step[a].max(0)) as f32;
+        (edge - start[a]) / dir[a]
+    }));
+    let mut t = 0.0;
+    while t <= max_dist {
+        if source.is_opaque(cell) {
+            return Some((cell, t));
+        }
+        let axis = if next.x < next.y && next.x < next.z { 0 } else if next.y < next.z { 1 } else { 2 };
+        t = next[axis];
+        cell[axis] += step[axis];
+        next[axis] += delta[axis];
+    }
+    None
+}
+
+#[test]
+fn morton_interleaves_axes() {
+    assert_eq!(morton(IVec3::X), 1);
+    assert_eq!(morton(IVec3::Y), 2);
+    assert_eq!(morton(IVec3::Z), 4);
+    assert_eq!(morton(IVec3::splat(3)), 0b111_111);
+
+    let mut codes: Vec<u64> = (0..16 * 16 * 16).map(|i| morton(IVec3::new(i % 16, i / 256, i / 16 % 16))).collect();
+    codes.sort_unstable();
+    codes.dedup();
+    assert_eq!(codes.len(), 16 * 16 * 16);
+}
+
+#[test]
+fn set_block_round_trips_and_removes() {
+    let mut world = open_world();
+    let pos = IVec3::new(3, 2, 5);
+    assert_eq!(world.set_block(pos, Block::new(WOOD)).unwrap().id, AIR);
+    world.set_meta(pos, LOG_Z);
+    assert_eq!(world.get_block(pos).id, WOOD);
+    assert_eq!(world.column_top(3, 5), Some(2));
+
+    // Removing returns what was there and leaves nothing behind, not even its meta
+    assert_eq!(world.set_block(pos, Block::AIR).unwrap().id, WOOD);
+    assert_eq!(world.get_block(pos).id, AIR);
+    assert_eq!(world.get_meta(pos), 0);
+    assert!(world.blocks.is_empty());
+    assert_eq!(world.column_top(3, 5), None);
+    assert_eq!(world.census()[WOOD as usize], 0);
+
+    // Clearing one voxel of a filled box keeps its neighbours
+    world.fill_box(IVec3::ZERO, IVec3::splat(2), Block::new(STONE)).unwrap();
+    world.set_block(IVec3::ONE, Block::AIR).unwrap();
+    for_box(IVec3::ZERO, IVec3::splat(2), |p| {
+        let want = if p == IVec3::ONE { AIR } else { STONE };
+        assert_eq!(world.get_block(p).id, want, "at {}", p);
+    });
+    assert_eq!(world.blocks.len(), 26);
+    world.validate().unwrap();
+}
+
+#[test]
+fn writes_match_dense() {
+    let mut rng = Rng(1);
+    let mut world = open_world();
+    let mut dense = alloc::vec![AIR; (N * N * N) as usize];
+
+    for round in 0..400 {
+        match rng.below(4) {
+            // Single voxel, the most common edit
+            0 | 1 => {
+                let (pos, id) = (rng.pos(), rng.id());
+                let old = world.set_block(pos, Block::new(id)).unwrap();
+                assert_eq!(old.id, dense[index(pos)]);
+                dense[index(pos)] = id;
+            }
+            2 => {
+                let (a, b) = (rng.pos(), rng.pos());
+                let (min, max, id) = (a.min(b), a.max(b), rng.id());
+                world.fill_box(min, max, Block::new(id)).unwrap();
+                for_box(min, max, |p| dense[index(p)] = id);
+            }
+            _ => {
+                let mut items: Vec<(IVec3, Block)> = (0..rng.below(24)).map(|_| (rng.pos(), Block::new(rng.id()))).collect();
+                // Later duplicates win, as they would written one by one
+                for &(pos, block) in &items {
+                    dense[index(pos)] = block.id;
+                }
+                world.insert_many(&mut items).unwrap();
+            }
+        }
+        if round % 20 == 0 {
+            world.compact();
+        }
+        assert_matches(&world, &dense);
+    }
+}
+
+#[test]
+fn fill_box_covers_unaligned_edges() {
+    let mut world = World::with_size(64);
+    let (min, max) = (IVec3::new(3, 0, 5), IVec3::new(60, 2, 41));
+    world.fill_box(min, max, Block::new(STONE)).unwrap();
+    assert_eq!(world.blocks.len(), 58 * 3 * 37);
+    for p in [min, max, IVec3::new(3, 2, 41), IVec3::new(60, 1, 5), IVec3::new(31, 1, 17)] {
+        assert_eq!(world.get_block(p).id, STONE, "inside at {}", p);
+    }
+    for p in [min - IVec3::X, max + IVec3::Z, IVec3::new(61, 1, 20), IVec3::new(30, 3, 20)] {
+        assert_eq!(world.get_block(p).id, AIR, "outside at {}", p);
+    }
+    assert_eq!(world.column_top(30, 20), Some(2));
+
+    // A hole punched through, then the whole box cleared, leaves nothing behind
+    world.fill_box(IVec3::new(10, 0, 10), IVec3::new(12, 2, 12), Block::AIR).unwrap();
+    assert_eq!(world.column_top(11, 11), None);
+    world.fill_box(min, max, Block::AIR).unwrap();
+    assert!(world.blocks.is_empty());
+    world.validate().unwrap();
+}
+
+#[test]
+fn clear_region_matches_dense() {
+    let mut rng = Rng(9);
+    for wrap in [WrapMode::Off, WrapMode::XZ] {
+        for _ in 0..20 {
+            let mut dense = random_dense(&mut rng);
+            let mut world = World::from_dense(IVec3::splat(N), &dense).unwrap();
+            (world.bounds, world.wrap) = (WorldBounds::Open, wrap);
+            world.relight();
+
+            let min = IVec3::new(rng.below(2 * N) - N, rng.below(N), rng.below(2 * N) - N);
+            let max = min + IVec3::new(rng.below(N), rng.below(N), rng.below(N));
+            world.clear_region(min, max);
+            for_box(min, max, |p| {
+                let p = world.wrap_pos(p);
+                if p.cmpge(IVec3::ZERO).all() && p.cmplt(IVec3::splat(N)).all() {
+                    dense[index(p)] = AIR;
+                }
+            });
+            assert_matches(&world, &dense);
+
+            // Relit from scratch, the same world has the same light
+            let mut fresh = World::from_dense(IVec3::splat(N), &dense).unwrap();
+            (fresh.bounds, fresh.wrap) = (WorldBounds::Open, wrap);
+            fresh.relight();
+            for_box(IVec3::ZERO, IVec3::splat(N - 1), |p| {
+                assert_eq!(world.light_at(p), fresh.light_at(p), "{:?} {} {} light at {}", wrap, min, max, p);
+            });
+        }
+    }
+}
+
+#[test]
+fn reset_regenerates_from_scratch() {
+    let kind = WorldKind::Flat { height: 3 };
+    let mut world = World::from_dense(IVec3::splat(N), &random_dense(&mut Rng(10))).unwrap();
+    world.wrap = WrapMode::XZ;
+    world.relight();
+    world.reset(kind).unwrap();
+
+    let mut fresh = World::with_size(N);
+    fresh.generate(kind, |_, _| {}).unwrap();
+    fresh.relight();
+    assert_eq!(world.wrap, WrapMode::XZ);
+    let (min, max) = (IVec3::ZERO, IVec3::splat(N - 1));
+    assert_eq!(world.to_dense(min, max), fresh.to_dense(min, max));
+    for_box(min, max, |p| assert_eq!(world.light_at(p), fresh.light_at(p), "light at {}", p));
+}
+
+#[test]
+fn flat_layers_match_column_block() {
+    for height in [0, 1, 2, 5] {
+        let mut world = World::with_size(N);
+        world.generate_flat(height, |_, _| {}).unwrap();
+        for y in 0..N {
+            let want = if y <= height { column_block(y, height, false) } else { AIR };
+            assert_eq!(world.get_block(IVec3::new(2, y, 5)).id, want, "height {} y {}", height, y);
+        }
+        assert_eq!(world.blocks.len(), (N * N * (height + 1)) as usize);
+    }
+}
+
+#[test]
+fn dense_round_trip() {
+    let mut rng = Rng(2);
+    let dense = random_dense(&mut rng);
+    let world = World::from_dense(IVec3::splat(N), &dense).unwrap();
+    assert_eq!(world.size, N);
+    assert_matches(&world, &dense);
+
+    // Bedrock fills whatever part of the box is below y=0
+    let mut world = world;
+    world.bounds = WorldBounds::Bedrock;
+    let below = world.to_dense(IVec3::new(0, -2, 0), IVec3::new(N - 1, 0, N - 1));
+    assert!(below[..(2 * N * N) as usize].iter().all(|&id| id == BEDROCK));
+    assert_eq!(&below[(2 * N * N) as usize..], &dense[..(N * N) as usize]);
+}
+
+#[test]
+fn raycast_matches_dense() {
+    let mut rng = Rng(3);
+    for _ in 0..8 {
+        let dense = DenseWorld(random_dense(&mut rng));
+        let mut world = World::from_dense(IVec3::splat(N), &dense.0).unwrap();
+        world.bounds = WorldBounds::Open;
+
+        for _ in 0..200 {
+            let origin = Vec3::new(rng.range(-3.0, 11.0), rng.range(-3.0, 11.0), rng.range(-3.0, 11.0));
+            let dir = rng.dir();
+            let hit = world.raycast(origin, dir, 32.0).map(|h| (h.pos, h.dist));
+            let expected = ray_march(&dense, origin, dir, 32.0);
+            match (hit, expected) {
+                (Some((pos, dist)), Some((want_pos, want_dist))) => {
+                    assert_eq!(pos, want_pos, "ray {} {}", origin, dir);
+                    assert!((dist - want_dist).abs() < 1e-3, "ray {} {}: {} vs {}", origin, dir, dist, want_dist);
+                }
+                (hit, expected) => assert_eq!(hit.is_some(), expected.is_some(), "ray {} {}", origin, dir),
+            }
+        }
+    }
+}
+
+#[test]
+fn axis_rays_hit_at_known_distances() {
+    let mut dense = alloc::vec![AIR; (N * N * N) as usize];
+    for (pos, id) in [((4, 1, 1), STONE), ((2, 1, 1), GLASS), ((1, 6, 1), BRICK), ((6, 0, 6), DIRT), ((6, 2, 6), WATER)] {
+        dense[index(IVec3::from(pos))] = id;
+    }
+    let mut world = World::from_dense(IVec3::splat(N), &dense).unwrap();
+    world.bounds = WorldBounds::Open;
+    let dense = DenseWorld(dense);
+
+    // Faces sit half a block before the centres, glass and water are seen through
+    let cases = [
+        (Vec3::new(-2.0, 1.0, 1.0), Vec3::X, Some((IVec3::new(4, 1, 1), 5.5))),
+        (Vec3::new(1.0, 9.0, 1.0), Vec3::NEG_Y, Some((IVec3::new(1, 6, 1), 2.5))),
+        (Vec3::new(6.0, 4.0, 6.0), Vec3::NEG_Y, Some((IVec3::new(6, 0, 6), 3.5))),
+        (Vec3::new(4.0, 1.0, 1.0), Vec3::NEG_X, Some((IVec3::new(4, 1, 1), 0.0))),
+        (Vec3::new(1.0, 1.0, -3.0), Vec3::Z, None),
+    ];
+    for (origin, dir, expected) in cases {
+        assert_eq!(ray_march(&dense, origin, dir, 32.0), expected, "dense ray {} {}", origin, dir);
+        assert_eq!(ray_march(&world, origin, dir, 32.0), expected, "block list ray {} {}", origin, dir);
+        let hit = world.raycast(origin, dir, 32.0).map(|h| (h.pos, h.dist));
+        assert_eq!(hit, expected, "raycast {} {}", origin, dir);
+    }
+}
+
+#[test]
+fn tall_builds_raise_the_ray_ceiling() {
+    let mut world = World::with_size(N);
+    world.fill_box(IVec3::ZERO, IVec3::new(N - 1, 1, N - 1), Block::new(STONE)).unwrap();
+    let up = |world: &World, x: i32, z: i32| world_ray(world, Vec3::new(x as f32, 5.0, z as f32), Vec3::Y);
+    assert_eq!(up(&world, 3, 3), None);
+
+    // Every write path that can raise the top has to let rays reach it
+    world.set_block(IVec3::new(3, 40, 3), Block::new(BRICK)).unwrap();
+    world.fill_box(IVec3::new(5, 60, 5), IVec3::new(5, 61, 5), Block::new(BRICK)).unwrap();
+    world.insert_many(&mut [(IVec3::new(1, 90, 1), Block::new(BRICK))]).unwrap();
+    world.set_block(IVec3::new(2, 50, 2), Block::new(GLASS)).unwrap();
+    assert_eq!(up(&world, 3, 3), Some((IVec3::new(3, 40, 3), 34.5)));
+    assert_eq!(up(&world, 5, 5), Some((IVec3::new(5, 60, 5), 54.5)));
+    assert_eq!(up(&world, 1, 1), Some((IVec3::new(1, 90, 1), 84.5)));
+    let glass = world.crossings::<4>(Vec3::new(2.0, 5.0, 2.0), Vec3::Y, 64.0);
+    assert_eq!(glass.iter().map(|c| c.pos).collect::<Vec<_>>(), [IVec3::new(2, 50, 2)]);
+
+    // Taking the top off lowers the ceiling again, the blocks below still render
+    world.set_block(IVec3::new(1, 90, 1), Block::AIR).unwrap();
+    assert_eq!(up(&world, 1, 1), None);
+    assert_eq!(world_ray(&world, Vec3::new(5.0, 100.0, 5.0), Vec3::NEG_Y), Some((IVec3::new(5, 61, 5), 38.5)));
+}
+
+#[test]
+fn solid_queries_match_get_block() {
+    let mut rng = Rng(7);
+    for (bounds, wrap) in [
+        (WorldBounds::Open, WrapMode::Off),
+        (WorldBounds::Bedrock, WrapMode::Off),
+        (WorldBounds::Bedrock, WrapMode::XZ),
+    ] {
+        let mut world = World::from_dense(IVec3::splat(N), &random_dense(&mut rng)).unwrap();
+        (world.bounds, world.wrap) = (bounds, wrap);
+        for _ in 0..200 {
+            // Boxes of up to 4x4x4, reaching past every side of the footprint
+            let min = IVec3::new(rng.below(N + 6) - 3, rng.below(N + 6) - 3, rng.below(N + 6) - 3);
+            let max = min + IVec3::new(rng.below(4), rng.below(4), rng.below(4));
+            let size = max - min + IVec3::ONE;
+            let mut expected = 0u64;
+            for_box(min, max, |p| {
+                let l = p - min;
+                expected |= (world.is_solid(p) as u64) << (l.x + size.x * (l.z + size.z * l.y));
+            });
+            assert_eq!(world.query_aabb_solid_mask(min, max), expected, "{:?} {:?} {} {}", bounds, wrap, min, max);
+            assert_eq!(world.query_aabb_solid(min, max), expected != 0, "{:?} {:?} {} {}", bounds, wrap, min, max);
+        }
+    }
+}
+
+#[test]
+fn neighbor_masks_match_get_block() {
+    let mut rng = Rng(8);
+    let world = World::from_dense(IVec3::splat(N), &random_dense(&mut rng)).unwrap();
+    // Includes the faces and corners of the footprint, and the bedrock below
+    for_box(IVec3::splat(-1), IVec3::splat(N), |pos| {
+        let mut mask6 = 0;
+        for (i, d) in NEIGHBORS6.iter().enumerate() {
+            mask6 |= (world.is_solid(pos + *d) as u8) << i;
+        }
+        let mut mask26 = 0;
+        let mut bit = 0;
+        for_box(IVec3::NEG_ONE, IVec3::ONE, |d| {
+            if d == IVec3::ZERO { return }
+            assert_eq!(world.solid_cube(pos) & cube_bit(d) != 0, world.is_solid(pos + d));
+            mask26 |= (world.is_solid(pos + d) as u32) << bit;
+            bit += 1;
+        });
+        assert_eq!(world.neighbor_mask6(pos), mask6, "at {}", pos);
+        assert_eq!(world.neighbor_mask26(pos), mask26, "at {}", pos);
+    });
+}
+
+#[test]
+fn crossings_keep_the_nearest() {
+    let mut rng = Rng(5);
+    let dense = random_dense(&mut rng);
+    let world = World::from_dense(IVec3::splat(N), &dense).unwrap();
+
+    for _ in 0..200 {
+        let origin = Vec3::new(rng.range(-3.0, 11.0), rng.range(-3.0, 11.0), rng.range(-3.0, 11.0));
+        let dir = rng.dir();
+        let all = world.crossings::<512>(origin, dir, 32.0);
+        let few = world.crossings::<4>(origin, dir, 32.0);
+        assert!(all.windows(2).all(|w| w[0].near <= w[1].near));
+        assert!(all.iter().all(|c| c.near < c.far && block_def(c.block.id).transparent));
+        assert_eq!(few.len(), all.len().min(4));
+        for (a, b) in few.iter().zip(all.iter()) {
+            assert_eq!((a.pos, a.near), (b.pos, b.near));
+        }
+    }
+}
+
+#[test]
+fn ray_tests_count_up_to_the_hit() {
+    let mut world = open_world();
+    for (x, id) in [(5, STONE), (3, GLASS), (1, BRICK)] {
+        world.set_block(IVec3::new(x, 1, 1), Block::new(id)).unwrap();
+    }
+    assert_eq!(world.ray_tests_per_pass(), 2);
+    // The stone comes first in the list but the brick is nearer, only found by the second test
+    assert_eq!(world.ray_tests(Vec3::new(-2.0, 1.0, 1.0), Vec3::X, 32.0), 2);
+    assert_eq!(world.ray_tests(Vec3::new(9.0, 1.0, 1.0), Vec3::NEG_X, 32.0), 1);
+    assert_eq!(world.ray_tests(Vec3::new(-2.0, 1.0, 4.0), Vec3::X, 32.0), 2);
+    assert_eq!(world.ray_tests(Vec3::new(-2.0, 9.0, 1.0), Vec3::Y, 32.0), 0);
+}
+
+#[test]
+fn edit_log_keeps_the_latest_set_blocks() {
+    let mut world = open_world();
+    let pos = IVec3::new(2, 3, 4);
+    world.set_block(pos, Block::new(STONE)).unwrap();
+    world.set_block(pos, Block::new(DIRT)).unwrap();
+    world.set_block(IVec3::new(N, 0, 0), Block::new(STONE)).unwrap();
+    let edits: Vec<_> = world.recent_edits().map(|e| (e.pos, e.old.id, e.new.id)).collect();
+    assert_eq!(edits, [(pos, STONE, DIRT), (pos, AIR, STONE)], "newest first, nothing out of bounds");
+
+    // Structures go in through set_block, so each of their blocks is an edit
+    world.spawn_structure(StructureId::House, IVec3::ZERO, true).unwrap();
+    let &(offset, id) = StructureId::House.template().last().unwrap();
+    let newest = world.recent_edits().next().unwrap();
+    assert_eq!((newest.pos, newest.new.id), (offset, id));
+
+    for i in 0..EDIT_LOG_LEN as i32 + 3 {
+        world.set_block(IVec3::new(i % N, 0, i / N), Block::new(BRICK)).unwrap();
+    }
+    assert_eq!(world.recent_edits().count(), EDIT_LOG_LEN);
+    let last = EDIT_LOG_LEN as i32 + 2;
+    assert_eq!(world.recent_edits().next().unwrap().pos, IVec3::new(last % N, 0, last / N));
+}
+
+#[test]
+fn houses_are_not_built_on_lakes() {
+    let mut world = World::with_size(2 * N);
+    world.fill_box(IVec3::ZERO, IVec3::new(2 * N - 1, 1, 2 * N - 1), Block::new(STONE)).unwrap();
+    world.fill_box(IVec3::new(0, 2, 0), IVec3::new(N - 1, 2, 2 * N - 1), Block::new(WATER)).unwrap();
+    assert_eq!(world.surface_height(0, 0), Some(2));
+    assert_eq!(world.column_top(0, 0), Some(1));
+
+    let origin = world.spawn_structure_near(StructureId::House, IVec3::new(N / 2, 0, N), N).unwrap();
+    let origin = origin.expect("dry land within reach");
+    assert!(origin.x >= N && origin.y == 2, "house at {}", origin);
+}
+
+#[test]
+fn structures_that_do_not_fit_leave_nothing_behind() {
+    let mut world = open_world();
+    world.fill_box(IVec3::ZERO, IVec3::new(N - 1, 0, N - 1), Block::new(STONE)).unwrap();
+    world.max_blocks = world.blocks.len() + 10;
+    assert!(world.spawn_structure(StructureId::House, IVec3::Y, false).is_err());
+    assert_eq!(world.blocks.len(), (N * N) as usize);
+    assert_eq!(world.recent_edits().count(), 0);
+}
+
+#[test]
+fn house_roof_logs_lie_along_x() {
+    let mut world = open_world();
+    world.fill_box(IVec3::ZERO, IVec3::splat(N - 1), Block::new(WOOD)).unwrap();
+    world.spawn_structure(StructureId::House, IVec3::ONE, true).unwrap();
+    let roof = IVec3::new(1, 4, 1);
+    assert_eq!(world.get_block(roof).id, WOOD);
+    assert_eq!(log_axis(world.get_meta(roof)), IVec3::X);
+    assert_eq!(world.get_meta(roof + IVec3::new(2, 0, 2)), 0, "glowstone");
+    // Logs the walls replaced lose their axis
+    assert_eq!(world.get_block(IVec3::ONE).id, BRICK);
+    assert_eq!(world.get_meta(IVec3::ONE), 0);
+    assert_eq!(log_axis(world.get_meta(IVec3::new(0, 4, 0))), IVec3::Y);
+}
+
+#[test]
+fn edits_mark_their_columns_dirty() {
+    let mut world = open_world();
+    world.fill_box(IVec3::ZERO, IVec3::new(N - 1, 1, N - 1), Block::new(STONE)).unwrap();
+    assert_eq!(world.take_dirty_columns(), Some((IVec2::ZERO, IVec2::splat(N - 1))));
+    assert_eq!(world.take_dirty_columns(), None);
+    let mut map = Heightmap::new(&world);
+
+    world.set_block(IVec3::new(2, 5, 3), Block::new(BRICK)).unwrap();
+    world.set_block(IVec3::new(6, 1, 1), Block::AIR).unwrap();
+    let (min, max) = world.take_dirty_columns().unwrap();
+    assert_eq!((min, max), (IVec2::new(2, 1), IVec2::new(6, 3)));
+
+    // Refreshing only the dirty box catches the snapshot up with a full rebuild
+    map.refresh(&world, min, max);
+    let fresh = Heightmap::new(&world);
+    for z in 0..N {
+        for x in 0..N {
+            assert_eq!(map.get(x, z), fresh.get(x, z), "column {} {}", x, z);
+        }
+    }
+    assert_eq!(map.get(2, 3), Some((5, BRICK)));
+}
+
+#[test]
+fn rle_round_trip() {
+    let mut rng = Rng(6);
+    let dense = random_dense(&mut rng);
+    let world = World::from_dense(IVec3::splat(N), &dense).unwrap();
+    let (min, max) = (IVec3::new(1, 0, 2), IVec3::new(6, 7, 4));
+    let rle = world.export_rle(min, max);
+
+    // Into a bigger world, over blocks the region's air has to clear
+    let mut copy = World::with_size(2 * N);
+    copy.fill_box(IVec3::ZERO, IVec3::splat(2 * N - 1), Block::new(DIRT)).unwrap();
+    let offset = IVec3::new(5, 3, 9);
+    copy.import_rle(&rle, offset).unwrap();
+    assert_eq!(copy.to_dense(offset, offset + max - min), world.to_dense(min, max));
+    assert_eq!(copy.get_block(offset - IVec3::ONE).id, DIRT);
+    copy.validate().unwrap();
+
+    // Runs that don't add up to the region, or a cut-off run, are rejected before any write
+    let mut short = rle.clone();
+    let last = short.len() - 2;
+    short[last] -= 1;
+    assert!(copy.import_rle(&short, IVec3::ZERO).is_err());
+    assert!(copy.import_rle(&rle[..rle.len() - 1], IVec3::ZERO).is_err());
+    assert_eq!(copy.get_block(IVec3::ZERO).id, DIRT);
+}
+
+#[test]
+fn serialize_round_trip() {
+    let mut rng = Rng(4);
+    let dense = random_dense(&mut rng);
+    let mut world = World::from_dense(IVec3::splat(N), &dense).unwrap();
+    world.save_count = 7;
+    world.spawn = Some(Vec3::new(1.5, 9.0, -2.25));
+
+    let mut loaded = World::deserialize(&world.serialize()).unwrap();
+    loaded.bounds = WorldBounds::Open;
+    assert_eq!(loaded.size, N);
+    assert_eq!(loaded.save_count, 7);
+    assert_eq!(loaded.spawn, world.spawn);
+    assert_matches(&loaded, &dense);
+
+    // Blocks outside the footprint are rejected even under a valid checksum
+    let mut outside = world.serialize();
+    let x_at = outside.len() - SAVE_BLOCK_LEN;
+    outside[x_at..x_at + 4].copy_from_slice(&N.to_le_bytes());
+    let crc = crc32(&outside[SAVE_CRC_AT + 4..]);
+    outside[SAVE_CRC_AT..SAVE_CRC_AT + 4].copy_from_slice(&crc.to_le_bytes());
+    assert!(World::deserialize(&outside).is_err());
+
+    // A flipped bit anywhere after the checksum field is caught
+    let mut damaged = world.serialize();
+    let last = damaged.len() - 1;
+    damaged[last] ^= 1;
+    assert!(World::deserialize(&damaged).is_err());
+}
+
+fn for_box(min: IVec3, max: IVec3, mut f: impl FnMut(IVec3)) {
+    for y in min.y..=max.y {
+        for z in min.z..=max.z {
+            for x in min.x..=max.x {
+                f(IVec3::new(x, y, z));
+            }
+        }
+    }
+}